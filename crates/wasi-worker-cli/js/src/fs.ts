@@ -52,12 +52,14 @@ class PipedWriter {
     position?: number
   ) => {
     this.writes ++;
+    // Only given range of the buffer is written
+    let written = stdoutBuffer.subarray(offset, offset + length);
     if (this.binFn) {
-      this.binFn(stdoutBuffer);
-      return stdoutBuffer.length;
+      this.binFn(written);
+      return length;
     }
 
-    let dataString = new TextDecoder("utf-8").decode(stdoutBuffer);
+    let dataString = new TextDecoder("utf-8").decode(written);
 
     if(this.strFn) {
       this.strFn(dataString);
@@ -67,7 +69,7 @@ class PipedWriter {
 
     // Record all of our stdout to show in the prompt
 
-    return stdoutBuffer.length;
+    return length;
   }
 
   mapBinFn(fn: (buffer: Uint8Array) => void) {
@@ -77,23 +79,83 @@ class PipedWriter {
   mapStrFn(fn: (msg: String) => void) {
     this.strFn = fn;
   }
+
+  // Calls fn once per complete message written by the worker
+  mapFrameFn(fn: (message: Uint8Array, channel: string) => void) {
+    let decoder = new FrameDecoder((frame) => {
+      let { channel, message } = decodeChannel(frame);
+      fn(message, channel);
+    });
+    this.binFn = (buffer) => decoder.push(buffer);
+  }
 }
 
+// Messages are passed to/from worker prefixed with 4 bytes little-endian length header
+// and channel name: [length][name length: u8][name][message], default channel name is empty
+const HEADER_LEN = 4;
+
+export function encodeFrame(message: Uint8Array, channel: string = ""): Uint8Array {
+  let name = new TextEncoder().encode(channel);
+  let length = 1 + name.length + message.length;
+  let frame = new Uint8Array(HEADER_LEN + length);
+  new DataView(frame.buffer).setUint32(0, length, true);
+  frame[HEADER_LEN] = name.length;
+  frame.set(name, HEADER_LEN + 1);
+  frame.set(message, HEADER_LEN + 1 + name.length);
+  return frame;
+}
+
+// Splits frame into channel name and message
+export function decodeChannel(frame: Uint8Array): { channel: string, message: Uint8Array } {
+  let length = frame[0];
+  let channel = new TextDecoder("utf-8").decode(frame.subarray(1, 1 + length));
+  return { channel, message: frame.subarray(1 + length) };
+}
+
+// Restores message boundaries from the stream of writes
+export class FrameDecoder {
+  buffer: Uint8Array;
+  onFrame: (frame: Uint8Array) => void;
+
+  constructor(onFrame: (frame: Uint8Array) => void) {
+    this.buffer = new Uint8Array(0);
+    this.onFrame = onFrame;
+  }
+
+  push(chunk: Uint8Array) {
+    let buffer = new Uint8Array(this.buffer.length + chunk.length);
+    buffer.set(this.buffer);
+    buffer.set(chunk, this.buffer.length);
+    let offset = 0;
+    while (buffer.length - offset >= HEADER_LEN) {
+      let length = new DataView(buffer.buffer, offset).getUint32(0, true);
+      if (buffer.length - offset - HEADER_LEN < length) {
+        break;
+      }
+      let start = offset + HEADER_LEN;
+      this.onFrame(buffer.slice(start, start + length));
+      offset = start + length;
+    }
+    this.buffer = buffer.slice(offset);
+  }
+}
+
+// Byte stream of framed incoming messages, worker reads it in chunks of any size
 class BufferedStdin {
   messages: Array<Uint8Array>;
-  lastPosition: number;
+  offset: number;
 
   constructor() {
     this.messages = new Array;
-    this.lastPosition = 0;
+    this.offset = 0;
   }
 
   bindToFd(stdin_fd: File) {
     stdin_fd.node.read = this.read;
   }
 
-  push(message: Uint8Array) {
-    this.messages.push(message);
+  push(message: Uint8Array, channel: string = "") {
+    this.messages.push(encodeFrame(message, channel));
   }
 
   read = (
@@ -102,26 +164,18 @@ class BufferedStdin {
     length: number = stdinBuffer.byteLength,
     position?: number
   ) => {
-    if (this.messages.length === 0) {
-      return 0;
-    } else if (position && position > 0 && position != this.lastPosition) {
-      this.error("BufferedStdin read on position not supported: " + position)
+    let read = 0;
+    while (read < length && this.messages.length > 0) {
+      let message = this.messages[0];
+      let chunk = message.subarray(this.offset, this.offset + length - read);
+      stdinBuffer.set(chunk, offset + read);
+      read += chunk.length;
+      this.offset += chunk.length;
+      if (this.offset >= message.length) {
+        this.messages.shift();
+        this.offset = 0;
+      }
     }
-    let message = this.messages.shift();
-    if (message && message.length < length) {
-      stdinBuffer.set(message);
-    } else if (message) {
-      this.error("Message does not fit passed stdin.read buffer: " + message.length)
-    } else {
-      return 0;
-    }
-    this.lastPosition += message.length;
-    return message.length;
-  }
-
-  error(message: String) {
-    let err = new Error("BufferedStdin error: " + message);
-    console.error(err);
-    throw err
+    return read;
   }
 }
@@ -10,8 +10,56 @@ const workerUrl = "worker.wasm"; //"wabench_app_wasi.wasm"; //
 let iamWorker = self;
 let instance: any = null;
 
+// Glue options are taken from worker script query, e.g. new Worker("worker.js?reserved"):
+// reserved - post $control, $error, $log and $trace traffic to the page, by default
+//   it is handled by the glue and only default and named channels reach the page
+// transfer - post messages as transferred ArrayBuffers instead of arrays of numbers
+// debug - log every incoming and outgoing message to console
+const options = new URLSearchParams(self.location.search);
+const postReserved = options.has("reserved");
+const transfer = options.has("transfer");
+const debug = options.has("debug");
+
 const workerFs = new WorkerFS();
 
+// Calls into worker export, ServiceWorker::close exits the process after closing
+// message was posted, which terminates this worker as well
+const callWorker = (call: () => any) => {
+  try {
+    return call();
+  } catch (e) {
+    if (e && typeof e.code === "number") {
+      console.log("Worker exited with code " + e.code);
+      self.close();
+    } else {
+      throw e;
+    }
+  }
+};
+
+// Timers armed by the worker via wasi_worker.set_timer import
+const timers = new Map<number, any>();
+
+const workerImports = {
+  set_timer: (id: number, delayMs: number) => {
+    clearTimeout(timers.get(id));
+    timers.set(id, setTimeout(() => {
+      timers.delete(id);
+      callWorker(() => instance.exports.timer_fired(id));
+    }, delayMs));
+  },
+  clear_timer: (id: number) => {
+    clearTimeout(timers.get(id));
+    timers.delete(id);
+  },
+  // Outgoing message of FileOptions::Host output, read straight from linear memory
+  post_message: (channelPtr: number, channelLen: number, ptr: number, len: number) => {
+    let memory = instance.exports.memory.buffer;
+    let channel = new TextDecoder("utf-8").decode(new Uint8Array(memory, channelPtr, channelLen));
+    postOutgoing(new Uint8Array(memory, ptr, len), channel);
+  }
+};
+
 let wasi = new WASI({
   preopenDirectories: {
     "/": "/"
@@ -52,13 +100,19 @@ const startWasiTask = async (file: string) => {
 
     // Instantiate the WebAssembly file
     instance = await WebAssembly.instantiate(module, {
-      wasi_snapshot_preview1: wasi.wasiImport
+      wasi_snapshot_preview1: wasi.wasiImport,
+      wasi_worker: workerImports
     });
 
     // Start the WebAssembly WASI instance!
     wasi.start(instance);
     console.log("worker has started");
 
+    // Poll futures spawned by worker main
+    if (typeof instance.exports.poll_tasks === "function") {
+      callWorker(() => instance.exports.poll_tasks());
+    }
+
     // @ts-ignore
     //workerFs.stdout.fd.write(Uint8Array.from([1,2,3]));
 
@@ -71,18 +125,167 @@ const startWasiTask = async (file: string) => {
   }
 };
 
+// Reads utf-8 string prefixed with u32 LE length at cursor and moves cursor past it,
+// see wasi_worker::framing::push_field
+const readField = (buffer: Uint8Array, cursor: { offset: number }) => {
+  let offset = cursor.offset;
+  let length = new DataView(buffer.buffer, buffer.byteOffset + offset, 4).getUint32(0, true);
+  cursor.offset = offset + 4 + length;
+  return new TextDecoder("utf-8").decode(buffer.subarray(offset + 4, offset + 4 + length));
+};
+
+// Error reports of $error channel, see wasi_worker::report
+const decodeReport = (buffer: Uint8Array) => {
+  let cursor = { offset: 1 };
+  let kind = buffer[0] === 1 ? "panic" : "error";
+  let message = readField(buffer, cursor);
+  let location = readField(buffer, cursor);
+  let backtrace = readField(buffer, cursor);
+  return { kind, message, location, backtrace };
+};
 
-workerFs.output.mapBinFn((buffer: Uint8Array) => {
-  console.log("Worker outgoing> " + buffer);
-  if (typeof iamWorker.postMessage === "function") {
-    iamWorker.postMessage(Array.from(buffer));
+// Log records of $log channel, see wasi_worker::logger
+const LOG_LEVELS = ["", "error", "warn", "info", "debug", "trace"];
+const decodeLogRecord = (buffer: Uint8Array) => {
+  let view = new DataView(buffer.buffer, buffer.byteOffset, buffer.byteLength);
+  let cursor = { offset: 9 };
+  let level = LOG_LEVELS[buffer[0]];
+  let timestamp = Number(view.getBigUint64(1, true));
+  let target = readField(buffer, cursor);
+  let modulePath = readField(buffer, cursor);
+  let message = readField(buffer, cursor);
+  return { level, timestamp, target, modulePath, message };
+};
+
+// Trace records of $trace channel, see wasi_worker::trace
+const TRACE_KINDS = ["enter", "exit", "event"];
+const decodeTraceRecord = (buffer: Uint8Array) => {
+  let view = new DataView(buffer.buffer, buffer.byteOffset, buffer.byteLength);
+  let cursor = { offset: 18 };
+  let kind = TRACE_KINDS[buffer[0]];
+  let level = LOG_LEVELS[buffer[1]];
+  let timestamp = Number(view.getBigUint64(2, true));
+  let span = view.getBigUint64(10, true).toString();
+  let name = readField(buffer, cursor);
+  let target = readField(buffer, cursor);
+  let fields = readField(buffer, cursor);
+  return { kind, level, timestamp, span, name, target, fields };
+};
+
+// Reserved channel traffic printed to console when page did not opt in
+const LOG_METHODS = [console.log, console.error, console.warn, console.info, console.debug, console.debug];
+const logReserved = (buffer: Uint8Array, channel: string) => {
+  if (channel === "$error") {
+    let { kind, message, location, backtrace } = decodeReport(buffer);
+    console.error("worker " + kind + "> " + message + " at " + location + "\n" + backtrace);
+  } else if (channel === "$log") {
+    let { target, message } = decodeLogRecord(buffer);
+    LOG_METHODS[buffer[0]].call(console, target + "> " + message);
+  } else if (channel === "$trace") {
+    let { kind, name, fields } = decodeTraceRecord(buffer);
+    console.debug("trace " + kind + "> " + name + " " + fields);
   }
-})
+};
+
+// Messages of default channel are posted as arrays of numbers, or transferred
+// ArrayBuffers with transfer option, messages of named channels as { channel, data }
+// objects with data in the same format.
+// With reserved option error reports are posted as { channel: "$error", error } objects,
+// log records as { channel: "$log", record } objects,
+// trace records as { channel: "$trace", record } objects,
+// control messages as { channel: "$control", event } objects, e.g. event "ready",
+// closing event carries exit code
+const CONTROL_EVENTS = ["ready", "init", "closing"];
+const postOutgoing = (buffer: Uint8Array, channel: string) => {
+  if (debug) {
+    console.log("Worker outgoing> " + channel + " " + buffer.length + " bytes");
+  }
+  if (channel.startsWith("$") && !postReserved) {
+    logReserved(buffer, channel);
+  } else if (typeof iamWorker.postMessage === "function") {
+    if (channel === "$control") {
+      let event = CONTROL_EVENTS[buffer[0]];
+      if (event === "closing") {
+        let code = new DataView(buffer.buffer, buffer.byteOffset + 1, 4).getInt32(0, true);
+        iamWorker.postMessage({ channel, event, code });
+      } else {
+        iamWorker.postMessage({ channel, event });
+      }
+    } else if (channel === "$trace") {
+      iamWorker.postMessage({ channel, record: decodeTraceRecord(buffer) });
+    } else if (channel === "$log") {
+      iamWorker.postMessage({ channel, record: decodeLogRecord(buffer) });
+    } else if (channel === "$error") {
+      iamWorker.postMessage({ channel, error: decodeReport(buffer) });
+    } else if (transfer) {
+      // Single copy out of worker memory, ownership of the copy is transferred
+      let data = buffer.slice().buffer;
+      if (channel === "") {
+        iamWorker.postMessage(data, [data]);
+      } else {
+        iamWorker.postMessage({ channel, data }, [data]);
+      }
+    } else {
+      // Pages such as yew agent bridge expect Array
+      let data = Array.from(buffer);
+      if (channel === "") {
+        iamWorker.postMessage(data);
+      } else {
+        iamWorker.postMessage({ channel, data });
+      }
+    }
+  }
+};
+
+workerFs.output.mapFrameFn(postOutgoing);
+
+// Writes [name length][name][message] right into buffer allocated in worker memory,
+// worker takes ownership of the buffer
+const messageReadyPtr = (message: Uint8Array, channel: string) => {
+  let name = new TextEncoder().encode(channel);
+  let len = 1 + name.length + message.length;
+  let ptr = instance.exports.wasi_worker_alloc(len);
+  let frame = new Uint8Array(instance.exports.memory.buffer, ptr, len);
+  frame[0] = name.length;
+  frame.set(name, 1);
+  frame.set(message, 1 + name.length);
+  return instance.exports.message_ready_ptr(ptr, len);
+};
+
+// Page sends arrays of numbers, typed arrays or ArrayBuffers
+const toBytes = (data: ArrayLike<number> | ArrayBuffer) =>
+  data instanceof Uint8Array ? data : new Uint8Array(data);
 
 iamWorker.onmessage = function(event) {
-  console.log("Worker incoming> "+ event.data);
-  workerFs.stdin.push(event.data);
-  console.log(instance.exports.message_ready());
+  let channel = "";
+  let message: Uint8Array;
+  if (event.data && event.data.channel === "$control" && event.data.init) {
+    // Init payload for Handler::on_init
+    let init = toBytes(event.data.init);
+    channel = "$control";
+    message = new Uint8Array(1 + init.length);
+    message[0] = 1;
+    message.set(init, 1);
+  } else if (event.data && typeof event.data.channel === "string") {
+    channel = event.data.channel;
+    message = toBytes(event.data.data);
+  } else {
+    message = toBytes(event.data);
+  }
+  if (debug) {
+    console.log("Worker incoming> " + channel + " " + message.length + " bytes");
+  }
+  callWorker(() => {
+    // Zero-copy exports call custom message_ready as well, see wasi_worker::export_message_ready
+    if (typeof instance.exports.message_ready_ptr === "function"
+        && typeof instance.exports.wasi_worker_alloc === "function") {
+      return messageReadyPtr(message, channel);
+    } else {
+      // Fallback for workers built without zero-copy exports
+      workerFs.stdin.push(message, channel);
+      return instance.exports.message_ready();
+    }
+  });
 };
 
 startWasiTask(workerUrl);
\ No newline at end of file
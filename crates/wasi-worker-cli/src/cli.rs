@@ -112,12 +112,14 @@ impl Cli {
         }
 
         let mut cmd = Command::new("cargo");
-        cmd.args(&[
+        cmd.args([
             "build",
             "--bin=worker",
             "--release",
             "--target=wasm32-wasi",
             "--target-dir=./target",
+            // Host imports provided by the glue
+            "--features=wasi-worker/host-timers,wasi-worker/host-output",
         ])
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit());
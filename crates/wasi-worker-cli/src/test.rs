@@ -29,11 +29,12 @@ impl TestData {
         let options = CopyOptions::new();
         fs::create_dir(&_self.test_root_dir).expect("create temp dir testdata/tmp");
         copy(&_self.source_dir, &_self.test_root_dir, &options)
-            .expect(&format!("setup project in {:?}", _self.test_dir));
+            .unwrap_or_else(|_| panic!("setup project in {:?}", _self.test_dir));
         _self
     }
     fn to_test_dir(&self) {
-        set_current_dir(&self.test_dir).expect(&format!("change dir to {:?}", &self.test_dir));
+        set_current_dir(&self.test_dir)
+            .unwrap_or_else(|_| panic!("change dir to {:?}", &self.test_dir));
     }
 }
 impl Drop for TestData {
@@ -48,10 +49,9 @@ fn test_install() {
     let testdata = TestData::setup("testcli", 1);
     // run install
     testdata.to_test_dir();
-    Cli::Install.exec().expect(&format!(
-        "run `wasiworker install` under {:?}",
-        testdata.test_dir
-    ));
+    Cli::Install
+        .exec()
+        .unwrap_or_else(|_| panic!("run `wasiworker install` under {:?}", testdata.test_dir));
     // validate resulting files
     let files = get_dir_content("./src/bin")
         .expect("dist dir does not exist after running `wasiworker deploy`")
@@ -64,10 +64,9 @@ fn test_deploy() {
     let testdata = TestData::setup("testcli.install", 2);
     // run install
     testdata.to_test_dir();
-    Cli::Deploy.exec().expect(&format!(
-        "run `wasiworker deploy` under {:?}",
-        testdata.test_dir
-    ));
+    Cli::Deploy
+        .exec()
+        .unwrap_or_else(|_| panic!("run `wasiworker deploy` under {:?}", testdata.test_dir));
     // check that all required files in dist exist
     let files = get_dir_content("./dist")
         .expect("dist dir exist after running `wasiworker deploy`")
@@ -2,7 +2,7 @@ use wasi_worker::*;
 
 struct MyWorker {}
 impl Handler for MyWorker {
-  fn on_message(&self, msg: &[u8]) -> std::io::Result<()> {
+  fn on_message(&self, msg: &[u8]) -> wasi_worker::Result<()> {
     // Process incoming message
     println!("My Worker got message: {:?}", msg);
     Ok(())
@@ -36,12 +36,3 @@ fn main() {
    * ```
    */
 }
-
-// this function will be called from worker.js when it receives message
-// In the future it will be substituted by poll_oneoff or thread::yield, 
-// though currently poll_oneoff does not return control to browser
-#[no_mangle]
-pub extern "C" fn message_ready() -> usize {
-  ServiceWorker::on_message()
-    .expect("ServiceWorker.on_message")
-}
\ No newline at end of file
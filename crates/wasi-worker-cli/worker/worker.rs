@@ -2,7 +2,7 @@ use wasi_worker::*;
 
 struct MyWorker {}
 impl Handler for MyWorker {
-  fn on_message(&self, msg: &[u8]) -> std::io::Result<()> {
+  fn on_message(&self, msg: &[u8]) -> wasi_worker::Result<()> {
     // Process incoming message
     println!("My Worker got message: {:?}", msg);
     Ok(())
@@ -14,12 +14,15 @@ fn main() {
   /* 
    * In WASI setup output will go to /output.bin
    * When compiled with other than wasi target default output is ./output.bin
+   * Incoming messages are read from stdin by default.
    * To override:
    * ```
-   * let opt = ServiceOptions { 
-   *   output: FileOptions::File("./testdata/output.bin".to_string()) 
-   * };
+   * let opt = ServiceOptions::default()
+   *   .with_input(FileOptions::File("./testdata/input.bin".to_string()))
+   *   .with_output(FileOptions::File("./testdata/output.bin".to_string()));
    * ```
+   * In WASI setup FileOptions::Host output passes messages to JS glue
+   * straight from worker memory, avoiding copies via /output.bin.
    */
   ServiceWorker::initialize(opt)
     .expect("ServiceWorker::initialize");
@@ -36,3 +39,6 @@ fn main() {
    * ```
    */
 }
+
+// message_ready export, called by worker.js on new message, is provided by wasi-worker.
+// To wrap it disable `message-ready` default feature and use wasi_worker::export_message_ready!
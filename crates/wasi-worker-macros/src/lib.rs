@@ -0,0 +1,468 @@
+//! Procedural macros for [wasi-worker](https://crates.io/crates/wasi-worker),
+//! enabled with its `macros` feature and used as `wasi_worker::main`,
+//! `wasi_worker::handler` and `wasi_worker::WorkerMessages`.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, DeriveInput, GenericArgument, ItemFn, ItemImpl, LitBool, LitStr, Path,
+    PathArguments, ReturnType, Type, TypeParamBound,
+};
+
+/// Turns function returning message handler into worker entry point.
+///
+/// Generated function initializes ServiceWorker with ServiceOptions from attributes,
+/// error reporting enabled, and sets returned handler as message handler.
+/// Handler kind is taken from return type: `impl Handler` (or any other type),
+/// `impl HandlerMut` or `impl AsyncHandler`, boxed trait objects are accepted too.
+///
+/// Attributes:
+/// * `input = "path"` - read messages from file instead of stdin
+/// * `output = "path"` - post messages to file instead of default output
+/// * `cleanup` - remove input and output files when worker is killed
+/// * `report_errors = false` - trap on handler errors instead of reporting them
+/// * `message_ready = path` - generate `message_ready` and zero-copy exports calling
+///   given `fn() -> usize`, requires `message-ready` and `message-ready-ptr` default
+///   features to be disabled, see `wasi_worker::export_message_ready`.
+///   Without this attribute exports are generated only when built-in ones are disabled.
+///
+/// Example usage:
+/// ```ignore
+/// use wasi_worker::{Handler, ServiceWorker};
+///
+/// struct MyWorker;
+/// impl Handler for MyWorker {
+///   fn on_message(&self, msg: &[u8]) -> wasi_worker::Result<()> {
+///     ServiceWorker::post_message(msg)
+///   }
+/// }
+///
+/// #[wasi_worker::main(output = "/out.bin", cleanup)]
+/// fn main() -> impl Handler {
+///   MyWorker
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn main(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut config = Config::default();
+    let parser = syn::meta::parser(|meta| config.parse(meta));
+    parse_macro_input!(attr with parser);
+    let item = parse_macro_input!(item as ItemFn);
+    expand(config, item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+mod messages;
+
+/// Generates `<Enum>Handler` trait with `on_<variant>` method per variant, which
+/// receives fields of the variant as arguments, and `dispatch` method passing
+/// message to the handler. See [macro@handler] to implement it with replies.
+///
+/// Example usage:
+/// ```ignore
+/// #[derive(serde::Deserialize, wasi_worker::WorkerMessages)]
+/// enum Request {
+///   Add(i32, i32),
+///   Greet { name: String },
+///   Ping,
+/// }
+/// // Generates:
+/// // trait RequestHandler {
+/// //   fn on_add(&self, arg0: i32, arg1: i32) -> wasi_worker::Result<()>;
+/// //   fn on_greet(&self, name: String) -> wasi_worker::Result<()>;
+/// //   fn on_ping(&self) -> wasi_worker::Result<()>;
+/// // }
+/// // impl Request {
+/// //   fn dispatch<H: RequestHandler + ?Sized>(self, handler: &H) -> wasi_worker::Result<()>;
+/// // }
+/// ```
+#[proc_macro_derive(WorkerMessages)]
+pub fn derive_worker_messages(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    messages::expand_derive(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Implements Handler for the type, which decodes incoming messages of enum
+/// deriving [WorkerMessages](derive@WorkerMessages) with given codec and passes
+/// every variant to the method of the impl block with the same name in snake case.
+///
+/// Every method taking `&self` in the impl block handles variant, other helpers
+/// belong to a separate impl block. Value returned by the method is encoded with
+/// codec and posted back with ServiceWorker::post_message, return type decides:
+/// * `()` or `Result<()>` - no reply, error is returned from Handler::on_message
+/// * `Option<T>` - reply if some
+/// * `Result<T>` and `Result<Option<T>>` - same after error is returned
+/// * any other type - reply
+///
+/// Return types are recognized by name, so type aliases are treated as replies.
+///
+/// Example usage:
+/// ```ignore
+/// use wasi_worker::codec::Json;
+///
+/// struct Calc;
+///
+/// #[wasi_worker::handler(Request, codec = Json)]
+/// impl Calc {
+///   fn add(&self, a: i32, b: i32) -> i32 {
+///     a + b
+///   }
+///   fn greet(&self, name: String) -> wasi_worker::Result<String> {
+///     Ok(format!("Hello, {}!", name))
+///   }
+///   fn ping(&self) {}
+/// }
+///
+/// ServiceWorker::set_message_handler(Box::new(Calc));
+/// ```
+#[proc_macro_attribute]
+pub fn handler(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as messages::HandlerArgs);
+    let item = parse_macro_input!(item as ItemImpl);
+    messages::expand_handler(args, item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+// Options collected from macro attributes
+struct Config {
+    input: Option<LitStr>,
+    output: Option<LitStr>,
+    cleanup: bool,
+    report_errors: bool,
+    message_ready: Option<Path>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            input: None,
+            output: None,
+            cleanup: false,
+            report_errors: true,
+            message_ready: None,
+        }
+    }
+}
+
+impl Config {
+    fn parse(&mut self, meta: syn::meta::ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("input") {
+            self.input = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("output") {
+            self.output = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("cleanup") {
+            self.cleanup = true;
+        } else if meta.path.is_ident("report_errors") {
+            self.report_errors = meta.value()?.parse::<LitBool>()?.value;
+        } else if meta.path.is_ident("message_ready") {
+            self.message_ready = Some(meta.value()?.parse()?);
+        } else {
+            return Err(meta.error("unsupported wasi_worker::main attribute"));
+        }
+        Ok(())
+    }
+
+    // ServiceOptions builder calls
+    fn options(&self) -> TokenStream2 {
+        let mut options = quote!(::wasi_worker::ServiceOptions::default());
+        if let Some(input) = &self.input {
+            options.extend(quote! {
+                .with_input(::wasi_worker::FileOptions::File(::std::string::String::from(#input)))
+            });
+        }
+        if let Some(output) = &self.output {
+            options.extend(quote! {
+                .with_output(::wasi_worker::FileOptions::File(::std::string::String::from(#output)))
+            });
+        }
+        if self.cleanup {
+            options.extend(quote!(.with_cleanup()));
+        }
+        if self.report_errors {
+            options.extend(quote!(.with_error_reporting()));
+        }
+        options
+    }
+}
+
+// How returned handler is installed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Handler,
+    HandlerMut,
+    AsyncHandler,
+}
+
+impl Kind {
+    fn of(bounds: impl IntoIterator<Item = TypeParamBound>) -> Self {
+        let traits = bounds.into_iter().filter_map(|bound| match bound {
+            TypeParamBound::Trait(bound) => bound.path.segments.last().map(|s| s.ident.clone()),
+            _ => None,
+        });
+        for ident in traits {
+            if ident == "HandlerMut" {
+                return Kind::HandlerMut;
+            } else if ident == "AsyncHandler" {
+                return Kind::AsyncHandler;
+            }
+        }
+        Kind::Handler
+    }
+}
+
+// Handler kind and whether it is already boxed, from return type of the function
+fn handler_kind(ty: &Type) -> (Kind, bool) {
+    match ty {
+        Type::ImplTrait(ty) => (Kind::of(ty.bounds.clone()), false),
+        Type::Path(ty) => {
+            let boxed = ty
+                .path
+                .segments
+                .last()
+                .filter(|segment| segment.ident == "Box")
+                .and_then(|segment| match &segment.arguments {
+                    PathArguments::AngleBracketed(args) => args.args.first(),
+                    _ => None,
+                });
+            match boxed {
+                Some(GenericArgument::Type(Type::TraitObject(object))) => {
+                    (Kind::of(object.bounds.clone()), true)
+                }
+                _ => (Kind::Handler, false),
+            }
+        }
+        _ => (Kind::Handler, false),
+    }
+}
+
+fn expand(config: Config, item: ItemFn) -> syn::Result<TokenStream2> {
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = item;
+    if !sig.inputs.is_empty() || !sig.generics.params.is_empty() || sig.asyncness.is_some() {
+        return Err(syn::Error::new_spanned(
+            sig,
+            "wasi_worker::main function takes no arguments and is not async",
+        ));
+    }
+    let ty = match &sig.output {
+        ReturnType::Type(_, ty) => ty,
+        ReturnType::Default => {
+            return Err(syn::Error::new_spanned(
+                sig,
+                "wasi_worker::main function returns message handler",
+            ))
+        }
+    };
+    let (kind, boxed) = handler_kind(ty);
+    let handler = if boxed {
+        quote!(handler())
+    } else {
+        quote!(::std::boxed::Box::new(handler()))
+    };
+    let set_handler = match kind {
+        Kind::Handler => quote!(set_message_handler),
+        Kind::HandlerMut => quote!(set_message_handler_mut),
+        Kind::AsyncHandler => quote!(set_async_handler),
+    };
+    let export = match &config.message_ready {
+        Some(path) => quote!(::wasi_worker::export_message_ready!(#path);),
+        None => quote!(::wasi_worker::__message_ready_export!();),
+    };
+    let ident = &sig.ident;
+    let options = config.options();
+    Ok(quote! {
+        #(#attrs)*
+        #vis fn #ident() {
+            fn handler() -> #ty #block
+            ::wasi_worker::ServiceWorker::initialize(#options)
+                .expect("ServiceWorker::initialize");
+            ::wasi_worker::ServiceWorker::#set_handler(#handler);
+        }
+
+        #export
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::messages::snake_case;
+    use super::{handler_kind, Kind};
+    use wasi_worker::codec::{Codec, Json};
+    use wasi_worker::framing::{read_message, write_message};
+    use wasi_worker::report::ERROR_CHANNEL;
+    use wasi_worker::{control, Handler, HandlerMut, ServiceWorker};
+
+    #[test]
+    fn handler_kinds() {
+        let kind = |ty: &str| handler_kind(&syn::parse_str(ty).unwrap());
+        assert_eq!(kind("impl Handler"), (Kind::Handler, false));
+        assert_eq!(kind("MyWorker"), (Kind::Handler, false));
+        assert_eq!(
+            kind("impl wasi_worker::HandlerMut"),
+            (Kind::HandlerMut, false)
+        );
+        assert_eq!(kind("Box<dyn AsyncHandler>"), (Kind::AsyncHandler, true));
+        assert_eq!(kind("Box<dyn Handler>"), (Kind::Handler, true));
+    }
+
+    #[test]
+    fn variant_methods() {
+        assert_eq!(snake_case("Ping"), "ping");
+        assert_eq!(snake_case("FindUser"), "find_user");
+        assert_eq!(snake_case("HTTPGet"), "http_get");
+        assert_eq!(snake_case("GetHTTP"), "get_http");
+        assert_eq!(snake_case("Sha256Sum"), "sha256_sum");
+    }
+
+    struct Echo;
+    impl Handler for Echo {
+        fn on_message(&self, msg: &[u8]) -> wasi_worker::Result<()> {
+            ServiceWorker::post_message(msg)
+        }
+    }
+
+    #[wasi_worker::main(
+        input = "./testdata/main_input.bin",
+        output = "./testdata/main_output.bin",
+        cleanup
+    )]
+    fn start() -> impl Handler {
+        Echo
+    }
+
+    struct Failing;
+    impl HandlerMut for Failing {
+        fn on_message(&mut self, _msg: &[u8]) -> wasi_worker::Result<()> {
+            Err(wasi_worker::Error::Decode("bad message".to_string()))
+        }
+    }
+
+    #[wasi_worker::main(
+        input = "./testdata/main_failing.bin",
+        output = "./testdata/main_reporting.bin",
+        cleanup
+    )]
+    fn start_mut() -> Box<dyn HandlerMut> {
+        Box::new(Failing)
+    }
+
+    #[test]
+    fn generated_setup() {
+        std::fs::create_dir_all("./testdata").expect("Create testdata");
+        let mut input = Vec::new();
+        write_message(&mut input, "", b"hello").unwrap();
+        std::fs::write("./testdata/main_input.bin", input).expect("Write input");
+        start();
+        assert_eq!(wasi_worker::process_message(), 5);
+        let output = std::fs::read("./testdata/main_output.bin").expect("Read output");
+        ServiceWorker::kill();
+
+        assert_eq!(
+            messages(&output),
+            vec![
+                (control::CONTROL_CHANNEL.to_string(), vec![control::READY]),
+                ("".to_string(), b"hello".to_vec()),
+            ]
+        );
+        std::fs::File::open("./testdata/main_output.bin")
+            .expect_err("output should been cleaned up");
+    }
+
+    #[test]
+    fn generated_error_reporting() {
+        std::fs::create_dir_all("./testdata").expect("Create testdata");
+        let mut input = Vec::new();
+        write_message(&mut input, "", b"hello").unwrap();
+        std::fs::write("./testdata/main_failing.bin", input).expect("Write input");
+        start_mut();
+        // Handler error is reported instead of trapping
+        assert_eq!(wasi_worker::process_message(), 0);
+        let output = std::fs::read("./testdata/main_reporting.bin").expect("Read output");
+        ServiceWorker::kill();
+
+        let channels: Vec<String> = messages(&output)
+            .into_iter()
+            .map(|(channel, _)| channel)
+            .collect();
+        assert_eq!(channels, vec![control::CONTROL_CHANNEL, ERROR_CHANNEL]);
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, wasi_worker::WorkerMessages)]
+    enum Request {
+        Add(i32, i32),
+        Greet { name: String },
+        FindUser(u32),
+        Ping,
+    }
+
+    struct Calc;
+
+    #[wasi_worker::handler(Request, codec = Json)]
+    impl Calc {
+        fn add(&self, a: i32, b: i32) -> i32 {
+            a + b
+        }
+
+        fn greet(&self, name: String) -> wasi_worker::Result<String> {
+            if name.is_empty() {
+                return Err(wasi_worker::Error::Decode("empty name".to_string()));
+            }
+            Ok(format!("Hello, {}!", name))
+        }
+
+        fn find_user(&self, id: u32) -> Option<String> {
+            Some("admin".to_string()).filter(|_| id == 0)
+        }
+
+        fn ping(&self) {}
+    }
+
+    #[test]
+    fn enum_messages() {
+        use wasi_worker::testing::MockHost;
+
+        let host = MockHost::new().expect("MockHost::new");
+        ServiceWorker::set_message_handler(Box::new(Calc));
+        host.clear_posted();
+        let send = |request: Request| host.send(&Json.encode(&request).unwrap());
+        send(Request::Add(2, 3)).unwrap();
+        send(Request::Greet {
+            name: "worker".to_string(),
+        })
+        .unwrap();
+        send(Request::FindUser(1)).unwrap();
+        send(Request::FindUser(0)).unwrap();
+        send(Request::Ping).unwrap();
+        let err = send(Request::Greet {
+            name: String::new(),
+        })
+        .expect_err("handler error is returned");
+        assert_eq!(err.to_string(), "Malformed message: empty name");
+        host.send(b"not json").expect_err("decode fails");
+
+        assert_eq!(
+            host.posted(),
+            vec![
+                b"5".to_vec(),
+                b"\"Hello, worker!\"".to_vec(),
+                b"\"admin\"".to_vec(),
+            ]
+        );
+    }
+
+    fn messages(mut data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut data).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+}
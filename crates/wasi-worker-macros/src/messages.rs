@@ -0,0 +1,304 @@
+// Expansion of derive(WorkerMessages) and handler attribute
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    Data, DeriveInput, Expr, Fields, FnArg, GenericArgument, Ident, ImplItem, ItemImpl,
+    PathArguments, ReturnType, Type,
+};
+
+// Name of the trait generated for enum with given name
+pub fn handler_trait(name: &Ident) -> Ident {
+    format_ident!("{}Handler", name)
+}
+
+// Name of the handler method which receives variant
+fn variant_method(variant: &Ident) -> Ident {
+    format_ident!("on_{}", snake_case(&variant.to_string()))
+}
+
+/// Splits words the way `heck` does: before an uppercase letter that follows
+/// a lowercase letter or digit, and before the last letter of an acronym
+/// followed by lowercase (`HTTPGet` becomes `http_get`).
+pub(crate) fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::new();
+    for (i, &ch) in chars.iter().enumerate() {
+        if ch.is_uppercase() {
+            if i > 0 {
+                let prev = chars[i - 1];
+                let next_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+                if prev.is_lowercase()
+                    || prev.is_ascii_digit()
+                    || (prev.is_uppercase() && next_lower)
+                {
+                    snake.push('_');
+                }
+            }
+            snake.extend(ch.to_lowercase());
+        } else {
+            snake.push(ch);
+        }
+    }
+    snake
+}
+
+pub fn expand_derive(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let DeriveInput {
+        vis,
+        ident,
+        generics,
+        data,
+        ..
+    } = input;
+    let data = match data {
+        Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "WorkerMessages can be derived for enums only",
+            ))
+        }
+    };
+    if !generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            generics,
+            "WorkerMessages can't be derived for generic enums",
+        ));
+    }
+    let handler = handler_trait(&ident);
+    let mut methods = Vec::new();
+    let mut arms = Vec::new();
+    for variant in data.variants {
+        let name = &variant.ident;
+        let method = variant_method(name);
+        let (pattern, params, args) = match &variant.fields {
+            Fields::Named(fields) => {
+                let names: Vec<&Ident> = fields
+                    .named
+                    .iter()
+                    .filter_map(|field| field.ident.as_ref())
+                    .collect();
+                let types = fields.named.iter().map(|field| &field.ty);
+                (
+                    quote!({ #(#names),* }),
+                    quote!(#(#names: #types),*),
+                    quote!(#(#names),*),
+                )
+            }
+            Fields::Unnamed(fields) => {
+                let names: Vec<Ident> = (0..fields.unnamed.len())
+                    .map(|i| format_ident!("arg{}", i))
+                    .collect();
+                let types = fields.unnamed.iter().map(|field| &field.ty);
+                (
+                    quote!((#(#names),*)),
+                    quote!(#(#names: #types),*),
+                    quote!(#(#names),*),
+                )
+            }
+            Fields::Unit => (quote!(), quote!(), quote!()),
+        };
+        methods.push(quote! {
+            fn #method(&self, #params) -> ::wasi_worker::Result<()>;
+        });
+        arms.push(quote! {
+            #ident::#name #pattern => handler.#method(#args),
+        });
+    }
+    let doc = format!(
+        "Receiver of {} messages, generated by derive(WorkerMessages)",
+        ident
+    );
+    Ok(quote! {
+        #[doc = #doc]
+        #vis trait #handler {
+            #(#methods)*
+        }
+
+        impl #ident {
+            /// Pass fields of the message to corresponding method of the handler
+            #vis fn dispatch<H: #handler + ?Sized>(self, handler: &H) -> ::wasi_worker::Result<()> {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+    })
+}
+
+// Arguments of handler attribute: `Enum, codec = Codec`
+pub struct HandlerArgs {
+    messages: syn::Path,
+    codec: Expr,
+}
+
+impl syn::parse::Parse for HandlerArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let messages = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        let key: Ident = input.parse()?;
+        if key != "codec" {
+            return Err(syn::Error::new_spanned(key, "expected `codec = ...`"));
+        }
+        input.parse::<syn::Token![=]>()?;
+        let codec = input.parse()?;
+        Ok(Self { messages, codec })
+    }
+}
+
+// How method return value becomes reply
+enum Reply {
+    // Unit, nothing to post
+    None,
+    // Result<()>, error is returned
+    Checked,
+    Value,
+    Optional,
+    CheckedValue,
+    CheckedOptional,
+}
+
+impl Reply {
+    fn of(output: &ReturnType) -> Self {
+        let ty = match output {
+            ReturnType::Default => return Reply::None,
+            ReturnType::Type(_, ty) => ty.as_ref(),
+        };
+        if is_unit(ty) {
+            return Reply::None;
+        }
+        if let Some(inner) = generic_of(ty, "Result") {
+            return if is_unit(inner) {
+                Reply::Checked
+            } else if generic_of(inner, "Option").is_some() {
+                Reply::CheckedOptional
+            } else {
+                Reply::CheckedValue
+            };
+        }
+        if generic_of(ty, "Option").is_some() {
+            Reply::Optional
+        } else {
+            Reply::Value
+        }
+    }
+}
+
+fn is_unit(ty: &Type) -> bool {
+    matches!(ty, Type::Tuple(tuple) if tuple.elems.is_empty())
+}
+
+// First type argument of the type with given name, e.g. T of Result<T>
+fn generic_of<'a>(ty: &'a Type, name: &str) -> Option<&'a Type> {
+    let segment = match ty {
+        Type::Path(ty) => ty.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != name {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+pub fn expand_handler(args: HandlerArgs, item: ItemImpl) -> syn::Result<TokenStream2> {
+    let HandlerArgs { messages, codec } = args;
+    if let Some((_, path, _)) = &item.trait_ {
+        return Err(syn::Error::new_spanned(
+            path,
+            "wasi_worker::handler is applied to inherent impl block",
+        ));
+    }
+    let mut handler = messages.clone();
+    let last = handler
+        .segments
+        .last_mut()
+        .ok_or_else(|| syn::Error::new_spanned(&messages, "expected messages enum"))?;
+    last.ident = handler_trait(&last.ident);
+    let self_ty = &item.self_ty;
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    let post = quote!(::wasi_worker::ServiceWorker::post_typed(&(#codec), &reply));
+    let mut methods = Vec::new();
+    for member in &item.items {
+        let method = match member {
+            ImplItem::Fn(method) => method,
+            _ => continue,
+        };
+        let sig = &method.sig;
+        let receiver = match sig.inputs.first() {
+            Some(FnArg::Receiver(receiver)) => receiver,
+            // Associated functions are not message handlers
+            _ => continue,
+        };
+        if receiver.reference.is_none() || receiver.mutability.is_some() {
+            return Err(syn::Error::new_spanned(
+                receiver,
+                "message handler methods take &self",
+            ));
+        }
+        let mut params = Vec::new();
+        let mut args = Vec::new();
+        for (i, input) in sig.inputs.iter().skip(1).enumerate() {
+            if let FnArg::Typed(input) = input {
+                let arg = Ident::new(&format!("__arg{}", i), Span::call_site());
+                let ty = &input.ty;
+                params.push(quote!(#arg: #ty));
+                args.push(arg);
+            }
+        }
+        let name = &sig.ident;
+        let call = quote!(<#self_ty>::#name(self, #(#args),*));
+        let body = match Reply::of(&sig.output) {
+            Reply::None => quote! {
+                #call;
+                Ok(())
+            },
+            Reply::Checked => quote!(Ok(#call?)),
+            Reply::Value => quote! {
+                let reply = #call;
+                #post
+            },
+            Reply::CheckedValue => quote! {
+                let reply = #call?;
+                #post
+            },
+            Reply::Optional => quote! {
+                match #call {
+                    Some(reply) => #post,
+                    None => Ok(()),
+                }
+            },
+            Reply::CheckedOptional => quote! {
+                match #call? {
+                    Some(reply) => #post,
+                    None => Ok(()),
+                }
+            },
+        };
+        let method = format_ident!("on_{}", name);
+        methods.push(quote! {
+            fn #method(&self, #(#params),*) -> ::wasi_worker::Result<()> {
+                #body
+            }
+        });
+    }
+    Ok(quote! {
+        #item
+
+        impl #impl_generics #handler for #self_ty #where_clause {
+            #(#methods)*
+        }
+
+        impl #impl_generics ::wasi_worker::Handler for #self_ty #where_clause {
+            fn on_message(&self, msg: &[u8]) -> ::wasi_worker::Result<()> {
+                let msg: #messages = ::wasi_worker::codec::Codec::decode(&(#codec), msg)?;
+                msg.dispatch(self)
+            }
+        }
+    })
+}
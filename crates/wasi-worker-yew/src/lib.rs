@@ -8,7 +8,7 @@
 //! ```
 //! use wasi_worker_yew::{ThreadedWASI, WASIAgent};
 //! use yew::agent::*;
-//! use wasi_worker::{FileOptions, ServiceOptions, ServiceWorker};
+//! use wasi_worker::{ServiceOptions, ServiceWorker};
 //!
 //! pub struct MyAgent;
 //! impl Agent for MyAgent {
@@ -18,7 +18,7 @@
 //!     type Output = String;
 //!     fn create(_link: AgentLink<Self>) -> Self { MyAgent { } }
 //!     fn update(&mut self, _msg: Self::Message) { /* ... */ }
-//!     fn handle(&mut self, _msg: Self::Input, _who: HandlerId) { /* */ }
+//!     fn handle_input(&mut self, _msg: Self::Input, _who: HandlerId) { /* */ }
 //!     // link to the JavaScript runner, worker instantiated from:
 //!     fn name_of_resource() -> &'static str { "worker.js" }
 //! };
@@ -26,7 +26,6 @@
 //! // In usual WASI setup with JS glue all output will be posted to /output.bin
 //! // Though in user filesystem output goes under ./output.bin
 //! let opt = ServiceOptions::default().with_cleanup();
-//! let output_file = match &opt.output { FileOptions::File(path) => path.clone() };
 //! ServiceWorker::initialize(opt)
 //!   .expect("ServiceWorker::initialize");
 //! ServiceWorker::set_message_handler(Box::new(WASIAgent::<MyAgent>::new()));
@@ -35,33 +34,48 @@
 pub use wasi_worker::{FileOptions, ServiceOptions, ServiceWorker};
 pub use yew::agent::{Agent, AgentLink, FromWorker, HandlerId, Packed, Public, ToWorker};
 
-use std::io;
+use std::cell::Cell;
 use wasi_worker::Handler;
-use yew::agent::{AgentScope, AgentLifecycleEvent, Responder};
+use yew::agent::{AgentLifecycleEvent, AgentScope, Responder};
 
 /// WASIAgent is the main executor and communication bridge for yew Agent with Reach = Public
 pub struct WASIAgent<T: Agent<Reach = Public>> {
     scope: AgentScope<T>,
+    // Agent is created once, whether run is called directly or from on_start
+    started: Cell<bool>,
 }
 
 impl<T: Agent<Reach = Public>> WASIAgent<T> {
     pub fn new() -> Self {
         Self {
             scope: AgentScope::<T>::new(),
+            started: Cell::new(false),
         }
     }
 }
 
+impl<T: Agent<Reach = Public>> Default for WASIAgent<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Implements rules to register a worker in a separate thread.
 pub trait ThreadedWASI {
     /// Creates Agent Scope, initialized AgentLink
     /// It will also create ServiceWorker and return it's instance
     /// ServiceWorker should be used by context to pass messages via on_message
-    fn run(&self) -> io::Result<()>;
+    ///
+    /// WASIAgent runs it from Handler::on_start when set as message handler,
+    /// consequent calls do nothing.
+    fn run(&self) -> wasi_worker::Result<()>;
 }
 
 impl<T: Agent<Reach = Public>> ThreadedWASI for WASIAgent<T> {
-    fn run(&self) -> io::Result<()> {
+    fn run(&self) -> wasi_worker::Result<()> {
+        if self.started.replace(true) {
+            return Ok(());
+        }
         let responder = WASIResponder {};
         let link = AgentLink::connect(&self.scope, responder);
         let upd = AgentLifecycleEvent::Create(link);
@@ -73,8 +87,17 @@ impl<T: Agent<Reach = Public>> ThreadedWASI for WASIAgent<T> {
 }
 
 impl<T: Agent<Reach = Public>> Handler for WASIAgent<T> {
-    fn on_message(&self, data: &[u8]) -> io::Result<()> {
-        let msg = ToWorker::<T::Input>::unpack(&data);
+    fn on_start(&self) -> wasi_worker::Result<()> {
+        self.run()
+    }
+
+    fn on_shutdown(&self) -> wasi_worker::Result<()> {
+        self.scope.send(AgentLifecycleEvent::Destroy);
+        Ok(())
+    }
+
+    fn on_message(&self, data: &[u8]) -> wasi_worker::Result<()> {
+        let msg = ToWorker::<T::Input>::unpack(data);
         match msg {
             ToWorker::Connected(id) => {
                 let upd = AgentLifecycleEvent::Connected(id);
@@ -89,9 +112,8 @@ impl<T: Agent<Reach = Public>> Handler for WASIAgent<T> {
                 self.scope.send(upd);
             }
             ToWorker::Destroy => {
-                let upd = AgentLifecycleEvent::Destroy;
-                self.scope.send(upd);
-                std::process::exit(1);
+                // Agent is destroyed in on_shutdown once worker is closed
+                ServiceWorker::close(0);
             }
         };
         Ok(())
@@ -116,7 +138,7 @@ impl<T: Agent<Reach = Public>> Responder<T> for WASIResponder {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use wasi_worker::{FileOptions, ServiceOptions};
+    use wasi_worker::{FileOptions, MemoryBuffer, ServiceOptions};
 
     struct MyAgent;
     impl Agent for MyAgent {
@@ -135,15 +157,82 @@ mod tests {
 
     #[test]
     fn it_works() {
-        let opt = ServiceOptions {
-            output: FileOptions::File("./testdata/output.bin".to_string()),
-            cleanup: true,
-        };
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default().with_output(FileOptions::Memory(output.clone()));
         ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
         ServiceWorker::set_message_handler(Box::new(WASIAgent::<MyAgent>::new()));
         let message = b"check";
         ServiceWorker::post_message(message).expect("ServiceWorker::post_message");
-        let data = std::fs::read("./testdata/output.bin").expect("Read testdata/output.bin");
-        assert_eq!(data, message);
+        // Agent is started when set as message handler
+        let loaded = FromWorker::<String>::WorkerLoaded.pack();
+        assert_eq!(output.messages().unwrap(), vec![loaded, message.to_vec()]);
+    }
+
+    #[test]
+    fn run_before_set_handler() {
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default().with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        // Sequence of previous releases, agent is created and loaded once
+        let agent = WASIAgent::<MyAgent>::new();
+        agent.run().expect("WASIAgent::run");
+        ServiceWorker::set_message_handler(Box::new(agent));
+        let loaded = FromWorker::<String>::WorkerLoaded.pack();
+        assert_eq!(output.messages().unwrap(), vec![loaded]);
+    }
+
+    // Agent of examples/yewworker, echoes input back
+    struct EchoAgent {
+        link: AgentLink<Self>,
+    }
+    impl Agent for EchoAgent {
+        type Reach = Public;
+        type Message = String;
+        type Input = String;
+        type Output = String;
+        fn create(link: AgentLink<Self>) -> Self {
+            EchoAgent { link }
+        }
+        fn update(&mut self, _msg: Self::Message) {}
+        fn handle_input(&mut self, msg: Self::Input, who: HandlerId) {
+            self.link.respond(who, msg);
+        }
+    }
+
+    // yew page bridge unpacks every message it receives as FromWorker, glue passes it
+    // default channel only, reserved channels are kept by the glue unless page opts in
+    #[test]
+    fn page_receives_agent_messages() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::set_message_handler(Box::new(WASIAgent::<EchoAgent>::new()));
+        let id = HandlerId::unpack(&[1, 0, 0, 0, 0, 0, 0, 0, 1]);
+        input.push_message(&ToWorker::<String>::Connected(id).pack());
+        input.push_message(&ToWorker::ProcessInput(id, "hello".to_string()).pack());
+        for _ in 0..2 {
+            ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        }
+        ServiceWorker::kill();
+
+        let mut contents = &output.contents()[..];
+        let mut page = Vec::new();
+        while let Some((channel, msg)) =
+            wasi_worker::framing::read_message(&mut contents).expect("read_message")
+        {
+            if channel.is_empty() {
+                page.push(FromWorker::<String>::unpack(&msg));
+            } else {
+                assert!(channel.starts_with('$'), "{} reaches the page", channel);
+            }
+        }
+        assert!(matches!(page[0], FromWorker::WorkerLoaded));
+        assert!(
+            matches!(&page[1], FromWorker::ProcessOutput(to, msg) if *to == id && msg == "hello")
+        );
+        assert_eq!(page.len(), 2);
     }
 }
@@ -1,2 +1,4 @@
 cargo build --target wasm32-wasi
-echo "hello from shell" | wasmtime --mapdir=/::./tmp ../../target/wasm32-wasi/debug/myworker.wasm
\ No newline at end of file
+# Message is prefixed with its length as 4 bytes little-endian header
+# and empty channel name (zero length byte)
+printf '\x11\x00\x00\x00\x00hello from shell' | wasmtime --mapdir=/::./tmp ../../target/wasm32-wasi/debug/myworker.wasm
\ No newline at end of file
@@ -2,7 +2,7 @@ use wasi_worker::*;
 
 struct MyWorker {}
 impl Handler for MyWorker {
-    fn on_message(&self, msg: &[u8]) -> std::io::Result<()> {
+    fn on_message(&self, msg: &[u8]) -> wasi_worker::Result<()> {
         // Process incoming message
         println!("My Worker got message: {:?}", msg);
         Ok(())
@@ -9,8 +9,13 @@ struct HandlerId(usize, bool);
 fn main() {
     let srzd = serde_json::to_string(&HandlerId(0, true)).unwrap();
     let hdl: wasi_worker_yew::HandlerId = serde_json::from_str(&srzd).unwrap();
-    let msg = ToWorker::<String>::ProcessInput(hdl, "hello".to_string());
-    std::io::stdout()
-        .write(&msg.pack())
+    let msg = ToWorker::<String>::ProcessInput(hdl, "hello".to_string()).pack();
+    // Worker expects every message prefixed with its length and channel name,
+    // default channel has empty name
+    let mut stdout = std::io::stdout();
+    stdout
+        .write_all(&(msg.len() as u32 + 1).to_le_bytes())
         .expect("Write to stdout");
+    stdout.write_all(&[0]).expect("Write to stdout");
+    stdout.write_all(&msg).expect("Write to stdout");
 }
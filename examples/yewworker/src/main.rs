@@ -31,14 +31,14 @@ fn main() {
     let opt = ServiceOptions::default().with_cleanup();
     let output_file = match &opt.output {
         FileOptions::File(path) => path.clone(),
+        _ => unreachable!("Default output is a file"),
     };
     ServiceWorker::initialize(opt).expect("ServiceWorker created");
 
-    // Following will create and initialize Agent
+    // Following will create Agent
     let agent = WASIAgent::<MyAgent>::new();
-    // It will run ThreadedWASI::run() to start Agent in WASI compatible context
-    agent.run().expect("Agent run");
-    // Attach Agent to ServiceWorker as message handler singleton
+    // Attach Agent to ServiceWorker as message handler singleton,
+    // it will run ThreadedWASI::run() to start Agent in WASI compatible context
     ServiceWorker::set_message_handler(Box::new(agent));
     ServiceWorker::post_message(b"message").expect("ServiceWorker.post_message");
 
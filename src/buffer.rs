@@ -0,0 +1,101 @@
+use super::framing::{read_message, write_message, DEFAULT_CHANNEL};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+/// In-memory channel which can be used as ServiceWorker input or output.
+///
+/// Clones share the same buffer, so test code can keep one handle to push
+/// incoming messages or inspect posted messages while ServiceWorker owns another.
+/// Reading consumes data from the front, writing appends to the back.
+#[derive(Clone, Default)]
+pub struct MemoryBuffer {
+    data: Rc<RefCell<VecDeque<u8>>>,
+}
+
+impl MemoryBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append single framed message of default channel, as host would do for incoming message
+    pub fn push_message(&self, msg: &[u8]) {
+        self.push_channel_message(DEFAULT_CHANNEL, msg)
+    }
+
+    /// Append single framed message of the named channel
+    pub fn push_channel_message(&self, channel: &str, msg: &[u8]) {
+        let mut writer = self;
+        write_message(&mut writer, channel, msg).expect("Write to memory buffer");
+    }
+
+    /// Decode all complete messages of default channel in the buffer without consuming them
+    pub fn messages(&self) -> io::Result<Vec<Vec<u8>>> {
+        self.channel_messages(DEFAULT_CHANNEL)
+    }
+
+    /// Decode all complete messages of the named channel without consuming them
+    pub fn channel_messages(&self, channel: &str) -> io::Result<Vec<Vec<u8>>> {
+        let data = self.contents();
+        let mut reader = &data[..];
+        let mut messages = Vec::new();
+        while let Some((name, msg)) = read_message(&mut reader)? {
+            if name == channel {
+                messages.push(msg);
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Decode messages of default channel and consume all messages in the buffer
+    pub fn take_messages(&self) -> io::Result<Vec<Vec<u8>>> {
+        let messages = self.messages()?;
+        self.clear();
+        Ok(messages)
+    }
+
+    /// Raw bytes currently held by the buffer
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.borrow().iter().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.borrow().is_empty()
+    }
+
+    pub fn clear(&self) {
+        self.data.borrow_mut().clear();
+    }
+}
+
+impl Read for MemoryBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.borrow_mut().read(buf)
+    }
+}
+
+impl Write for &MemoryBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.borrow_mut().extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for MemoryBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
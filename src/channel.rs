@@ -0,0 +1,57 @@
+use super::codec::Codec;
+use super::{Error, Result, ServiceWorker};
+
+// Prefix of channels used by wasi-worker itself, e.g. `$control` and `$error`
+const RESERVED_PREFIX: char = '$';
+
+/// Channels with reserved names can't be used by the worker code,
+/// name length has to fit into frame, see [framing](crate::framing)
+pub(crate) fn check_name(name: &str) -> Result<()> {
+    if name.starts_with(RESERVED_PREFIX) {
+        return Err(Error::ReservedChannel(name.to_string()));
+    }
+    if name.len() > u8::MAX as usize {
+        return Err(Error::ChannelNameTooLong(name.len()));
+    }
+    Ok(())
+}
+
+/// Sender of messages to the named channel, see ServiceWorker::channel.
+///
+/// Main application receives messages tagged with channel name,
+/// and messages it sends to the channel are passed to the handler
+/// set via ServiceWorker::set_channel_handler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Channel {
+    name: String,
+}
+
+impl Channel {
+    pub(crate) fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Post message to the channel, names are limited to 255 bytes
+    pub fn post_message(&self, msg: &[u8]) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "post_message",
+            channel = self.name.as_str(),
+            len = msg.len()
+        )
+        .entered();
+        ServiceWorker::post_channel_message(&self.name, msg)
+    }
+
+    /// Encode message with given codec and post it to the channel
+    pub fn post_typed<T, C: Codec<T>>(&self, codec: &C, msg: &T) -> Result<()> {
+        let data = codec.encode(msg)?;
+        self.post_message(&data)
+    }
+}
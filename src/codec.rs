@@ -0,0 +1,229 @@
+//! Typed messages on top of binary ServiceWorker channels.
+//!
+//! Codec converts messages to and from bytes, TypedHandler receives decoded
+//! messages and its replies get encoded and posted back via ServiceWorker.
+//! Serde based codecs are available with cargo features:
+//! `json`, `bincode`, `cbor` and `msgpack`.
+//!
+//! Example usage:
+//! ```
+//! use wasi_worker::codec::{Codec, Typed, TypedHandler};
+//! use wasi_worker::{Error, ServiceWorker};
+//!
+//! // Usually one of provided serde codecs, e.g. wasi_worker::codec::Json
+//! struct Utf8;
+//! impl Codec<String> for Utf8 {
+//!   fn encode(&self, msg: &String) -> wasi_worker::Result<Vec<u8>> {
+//!     Ok(msg.as_bytes().to_vec())
+//!   }
+//!   fn decode(&self, data: &[u8]) -> wasi_worker::Result<String> {
+//!     String::from_utf8(data.to_vec()).map_err(|err| Error::Decode(err.to_string()))
+//!   }
+//! }
+//!
+//! struct Greeter;
+//! impl TypedHandler<String, String> for Greeter {
+//!   fn on_message(&self, name: String) -> wasi_worker::Result<Option<String>> {
+//!     Ok(Some(format!("Hello, {}!", name)))
+//!   }
+//! }
+//!
+//! ServiceWorker::set_message_handler(Box::new(Typed::new(Utf8, Greeter)));
+//! ```
+use super::{Handler, Result, ServiceWorker};
+use std::marker::PhantomData;
+
+/// Converts messages of type T to and from bytes.
+///
+/// Encode and decode failures are reported as Error::Decode.
+pub trait Codec<T> {
+    fn encode(&self, msg: &T) -> Result<Vec<u8>>;
+    fn decode(&self, data: &[u8]) -> Result<T>;
+}
+
+/// Handler for decoded incoming messages, see [Typed] for ServiceWorker adapter.
+pub trait TypedHandler<In, Out> {
+    /// Returned message, if any, is encoded and posted back to main application
+    fn on_message(&self, msg: In) -> Result<Option<Out>>;
+}
+
+/// Adapter which allows to use TypedHandler as ServiceWorker Handler
+pub struct Typed<C, H, In, Out> {
+    codec: C,
+    handler: H,
+    _types: PhantomData<fn(In) -> Out>,
+}
+
+impl<C, H, In, Out> Typed<C, H, In, Out>
+where
+    C: Codec<In> + Codec<Out>,
+    H: TypedHandler<In, Out>,
+{
+    pub fn new(codec: C, handler: H) -> Self {
+        Self {
+            codec,
+            handler,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<C, H, In, Out> Handler for Typed<C, H, In, Out>
+where
+    C: Codec<In> + Codec<Out>,
+    H: TypedHandler<In, Out>,
+{
+    fn on_message(&self, msg: &[u8]) -> Result<()> {
+        let msg: In = self.codec.decode(msg)?;
+        if let Some(reply) = self.handler.on_message(msg)? {
+            ServiceWorker::post_typed(&self.codec, &reply)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(
+    feature = "json",
+    feature = "bincode",
+    feature = "cbor",
+    feature = "msgpack"
+))]
+fn invalid_data<E: std::fmt::Display>(err: E) -> super::Error {
+    super::Error::Decode(err.to_string())
+}
+
+/// JSON codec via serde_json
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Json {
+    fn encode(&self, msg: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(msg).map_err(invalid_data)
+    }
+    fn decode(&self, data: &[u8]) -> Result<T> {
+        serde_json::from_slice(data).map_err(invalid_data)
+    }
+}
+
+/// Bincode codec
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Bincode {
+    fn encode(&self, msg: &T) -> Result<Vec<u8>> {
+        bincode::serialize(msg).map_err(invalid_data)
+    }
+    fn decode(&self, data: &[u8]) -> Result<T> {
+        bincode::deserialize(data).map_err(invalid_data)
+    }
+}
+
+/// CBOR codec via ciborium
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Cbor {
+    fn encode(&self, msg: &T) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ciborium::into_writer(msg, &mut data).map_err(invalid_data)?;
+        Ok(data)
+    }
+    fn decode(&self, data: &[u8]) -> Result<T> {
+        ciborium::from_reader(data).map_err(invalid_data)
+    }
+}
+
+/// MessagePack codec via rmp-serde, structs are encoded as maps
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for MessagePack {
+    fn encode(&self, msg: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(msg).map_err(invalid_data)
+    }
+    fn decode(&self, data: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(data).map_err(invalid_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, FileOptions, MemoryBuffer, ServiceOptions};
+
+    struct Number;
+    impl Codec<u32> for Number {
+        fn encode(&self, msg: &u32) -> Result<Vec<u8>> {
+            Ok(msg.to_le_bytes().to_vec())
+        }
+        fn decode(&self, data: &[u8]) -> Result<u32> {
+            use std::convert::TryInto;
+            let bytes = data
+                .try_into()
+                .map_err(|_| Error::Decode("expected 4 bytes".to_string()))?;
+            Ok(u32::from_le_bytes(bytes))
+        }
+    }
+
+    struct Double;
+    impl TypedHandler<u32, u32> for Double {
+        fn on_message(&self, msg: u32) -> Result<Option<u32>> {
+            Ok(Some(msg * 2))
+        }
+    }
+
+    #[test]
+    fn typed_handler() {
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default().with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        let handler = Typed::new(Number, Double);
+        handler.on_message(&21u32.to_le_bytes()).unwrap();
+        let err = handler
+            .on_message(b"not a number")
+            .expect_err("decode should fail");
+        assert!(matches!(err, Error::Decode(_)));
+        ServiceWorker::kill();
+        assert_eq!(
+            output.messages().unwrap(),
+            vec![42u32.to_le_bytes().to_vec()]
+        );
+    }
+
+    #[cfg(any(
+        feature = "json",
+        feature = "bincode",
+        feature = "cbor",
+        feature = "msgpack"
+    ))]
+    #[test]
+    fn serde_codecs() {
+        #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+        struct Tile {
+            id: u64,
+            data: Vec<u8>,
+        }
+        fn roundtrip<C: Codec<Tile>>(codec: C) {
+            let tile = Tile {
+                id: 7,
+                data: vec![1, 2, 3],
+            };
+            let data = codec.encode(&tile).unwrap();
+            assert_eq!(codec.decode(&data).unwrap(), tile);
+            let err = codec.decode(&[0xff]).expect_err("decode should fail");
+            assert!(matches!(err, Error::Decode(_)));
+        }
+        #[cfg(feature = "json")]
+        roundtrip(Json);
+        #[cfg(feature = "bincode")]
+        roundtrip(Bincode);
+        #[cfg(feature = "cbor")]
+        roundtrip(Cbor);
+        #[cfg(feature = "msgpack")]
+        roundtrip(MessagePack);
+    }
+}
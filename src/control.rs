@@ -0,0 +1,20 @@
+//! Lifecycle control messages exchanged with the host on the reserved `$control` channel.
+//!
+//! Worker posts:
+//! * `[0]` ready - message handler is set and worker processes messages,
+//!   posted once after ServiceWorker is initialized and message handler is set
+//! * `[2][exit code: i32 LE]` closing - worker is terminating, see ServiceWorker::close
+//!
+//! Host sends:
+//! * `[1][payload]` init - payload is passed to Handler::on_init of message handler,
+//!   kept until message handler is set if it arrives earlier
+
+/// Channel of lifecycle control messages
+pub const CONTROL_CHANNEL: &str = "$control";
+
+/// Worker is ready to process messages
+pub const READY: u8 = 0;
+/// Init payload from the host
+pub const INIT: u8 = 1;
+/// Worker is terminating
+pub const CLOSING: u8 = 2;
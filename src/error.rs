@@ -0,0 +1,89 @@
+use std::fmt;
+use std::io;
+
+/// Result of ServiceWorker operations and message handlers,
+/// error type parameter keeps `use wasi_worker::*` compatible with std Result
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Error of ServiceWorker operations and message handlers
+#[derive(Debug)]
+pub enum Error {
+    /// ServiceWorker was not initialized or was killed
+    NotInitialized,
+    /// There is no handler for the channel, default channel name is empty
+    NoHandler(String),
+    /// ServiceWorker state is in use by the call this one was made from
+    Reentrant,
+    /// Channel names starting with `$` are reserved for wasi-worker
+    ReservedChannel(String),
+    /// Channel name of given length in bytes does not fit into frame, limit is 255
+    ChannelNameTooLong(usize),
+    /// Rpc request for method which is not registered, see rpc::Rpc
+    NoMethod(String),
+    /// Queue of messages waiting for handler is full, see OverflowPolicy::Error
+    QueueFull,
+    /// Message of given size does not fit into frame
+    MessageTooLarge(usize),
+    /// Message could not be encoded or decoded
+    Decode(String),
+    /// Failure of underlying input or output
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotInitialized => write!(f, "Service was not initialized"),
+            Error::NoHandler(channel) if channel.is_empty() => {
+                write!(f, "Message handler was not set")
+            }
+            Error::NoHandler(channel) => write!(f, "Handler of channel {} was not set", channel),
+            Error::Reentrant => write!(f, "Service is already in use by the calling code"),
+            Error::ReservedChannel(channel) => {
+                write!(f, "Channel name {} is reserved for wasi-worker", channel)
+            }
+            Error::ChannelNameTooLong(len) => {
+                write!(f, "Channel name of {} bytes is longer than 255 bytes", len)
+            }
+            Error::NoMethod(method) => write!(f, "Unknown method {}", method),
+            Error::QueueFull => write!(f, "Message queue is full, message handler was not set"),
+            Error::MessageTooLarge(len) => {
+                write!(f, "Message of {} bytes does not fit into frame", len)
+            }
+            Error::Decode(description) => write!(f, "Malformed message: {}", description),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+// Allows to use ServiceWorker calls with `?` in functions returning io::Result
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        let kind = match err {
+            Error::Io(err) => return err,
+            Error::NotInitialized => io::ErrorKind::NotConnected,
+            Error::NoMethod(_) => io::ErrorKind::NotFound,
+            Error::MessageTooLarge(_)
+            | Error::ReservedChannel(_)
+            | Error::ChannelNameTooLong(_) => io::ErrorKind::InvalidInput,
+            Error::Decode(_) => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,127 @@
+//! Single threaded executor for futures spawned inside the worker.
+//!
+//! Worker does not own its event loop, host calls into it via exported functions.
+//! Pending futures are kept between calls and polled when woken, on every
+//! ServiceWorker::on_message (message_ready), after timer callbacks (timer_fired)
+//! and on explicit host wakeup (poll_tasks), which JS glue calls once worker main returns.
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Wake, Waker};
+
+/// Boxed future which is not required to be Send
+pub type LocalFuture<T> = Pin<Box<dyn Future<Output = T>>>;
+
+type ReadyQueue = Arc<Mutex<VecDeque<usize>>>;
+
+thread_local! {
+  static TASKS: RefCell<HashMap<usize, LocalFuture<()>>> = RefCell::new(HashMap::new());
+  static READY: RefCell<ReadyQueue> = RefCell::new(ReadyQueue::default());
+  static NEXT_ID: Cell<usize> = const { Cell::new(0) };
+  // Tasks being polled, with flag set when they are woken by nested poll_pending
+  static RUNNING: RefCell<HashMap<usize, bool>> = RefCell::new(HashMap::new());
+}
+
+struct TaskWaker {
+    id: usize,
+    ready: ReadyQueue,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Ok(mut ready) = self.ready.lock() {
+            ready.push_back(self.id);
+        }
+    }
+}
+
+/// Spawn future on the worker executor, it will be first polled on next poll_pending
+pub fn spawn_local<F: Future<Output = ()> + 'static>(future: F) {
+    let id = NEXT_ID.with(|next| {
+        let id = next.get();
+        next.set(id.wrapping_add(1));
+        id
+    });
+    TASKS.with(|tasks| tasks.borrow_mut().insert(id, Box::pin(future)));
+    READY.with(|ready| ready.borrow().lock().expect("executor queue").push_back(id));
+}
+
+/// Poll woken futures until none is ready, returns number of pending futures.
+///
+/// Futures may spawn other futures or wake each other while being polled.
+/// It may be called from a polled future too, e.g. by nested message dispatch.
+pub fn poll_pending() -> usize {
+    let ready = READY.with(|ready| ready.borrow().clone());
+    loop {
+        let next = ready.lock().expect("executor queue").pop_front();
+        let id = match next {
+            Some(id) => id,
+            None => break,
+        };
+        // Task is taken out for the time of polling, so it can spawn new tasks
+        let task = TASKS.with(|tasks| tasks.borrow_mut().remove(&id));
+        let mut task = match task {
+            Some(task) => task,
+            None => {
+                // Wake of the task which is polled by outer call, it is repeated
+                // once the task is back
+                RUNNING.with(|running| {
+                    if let Some(woken) = running.borrow_mut().get_mut(&id) {
+                        *woken = true;
+                    }
+                });
+                continue;
+            }
+        };
+        RUNNING.with(|running| running.borrow_mut().insert(id, false));
+        let waker = Waker::from(Arc::new(TaskWaker {
+            id,
+            ready: ready.clone(),
+        }));
+        let mut cx = Context::from_waker(&waker);
+        let poll = task.as_mut().poll(&mut cx);
+        let woken = RUNNING.with(|running| running.borrow_mut().remove(&id));
+        // Executor was cleared while the task was polled
+        if !READY.with(|current| Arc::ptr_eq(&current.borrow(), &ready)) {
+            break;
+        }
+        if poll.is_pending() {
+            TASKS.with(|tasks| tasks.borrow_mut().insert(id, task));
+            if woken == Some(true) {
+                ready.lock().expect("executor queue").push_back(id);
+            }
+        }
+    }
+    TASKS.with(|tasks| tasks.borrow().len())
+}
+
+/// Drop all pending futures.
+///
+/// Ready queue is replaced, so future which is being polled is dropped
+/// once it yields and wakers of dropped futures have no effect.
+pub(crate) fn clear() {
+    let tasks = TASKS.with(|tasks| tasks.replace(HashMap::new()));
+    drop(tasks);
+    READY.with(|ready| ready.replace(ReadyQueue::default()));
+}
+
+/// Futures of worker instance which is not entered, see ServiceWorker::enter.
+///
+/// Wakers keep reference to the ready queue of their instance.
+#[derive(Default)]
+pub(crate) struct Tasks {
+    tasks: HashMap<usize, LocalFuture<()>>,
+    ready: ReadyQueue,
+}
+
+/// Exchange futures of the current worker with given ones
+pub(crate) fn swap(other: &mut Tasks) {
+    TASKS.with(|tasks| std::mem::swap(&mut *tasks.borrow_mut(), &mut other.tasks));
+    READY.with(|ready| std::mem::swap(&mut *ready.borrow_mut(), &mut other.ready));
+}
@@ -0,0 +1,184 @@
+//! Length-prefixed framing of messages passed over input and output channels.
+//!
+//! Every frame is preceded by 4 bytes header holding frame length as
+//! little-endian u32. Stream oriented channels (stdin, memfs files) may
+//! split or merge writes, header allows to restore message boundaries.
+//!
+//! Frame of the message carries name of the channel it belongs to:
+//! `[frame length: u32 LE][channel name length: u8][channel name][payload]`,
+//! messages of ServiceWorker::post_message go to the default channel with empty name.
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Size of frame header in bytes
+pub const HEADER_LEN: usize = 4;
+
+/// Name of the channel used by ServiceWorker::post_message and message handler
+pub const DEFAULT_CHANNEL: &str = "";
+
+/// Write single message of the channel to the writer
+pub fn write_message<W: Write + ?Sized>(
+    writer: &mut W,
+    channel: &str,
+    msg: &[u8],
+) -> io::Result<()> {
+    let name = channel.as_bytes();
+    if name.len() > u8::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Channel name is too long",
+        ));
+    }
+    let len = 1 + name.len() + msg.len();
+    if len > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Message does not fit into frame",
+        ));
+    }
+    writer.write_all(&(len as u32).to_le_bytes())?;
+    writer.write_all(&[name.len() as u8])?;
+    writer.write_all(name)?;
+    writer.write_all(msg)?;
+    writer.flush()
+}
+
+/// Read single message from the reader, returns channel name and payload.
+///
+/// Returns `Ok(None)` when reader has no more data, see [read_frame].
+pub fn read_message<R: Read + ?Sized>(reader: &mut R) -> io::Result<Option<(String, Vec<u8>)>> {
+    match read_frame(reader)? {
+        Some(frame) => split_channel(frame).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Split frame without length header into channel name and payload
+pub fn split_channel(mut frame: Vec<u8>) -> io::Result<(String, Vec<u8>)> {
+    let (channel, offset) = parse_channel(&frame)?;
+    let channel = channel.to_string();
+    Ok((channel, frame.split_off(offset)))
+}
+
+/// Parse channel name of the frame without length header,
+/// returns channel name and offset of the payload in the frame
+pub fn parse_channel(frame: &[u8]) -> io::Result<(&str, usize)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Malformed channel name");
+    let len = *frame.first().ok_or_else(invalid)? as usize;
+    let name = frame.get(1..1 + len).ok_or_else(invalid)?;
+    let channel = std::str::from_utf8(name).map_err(|_| invalid())?;
+    Ok((channel, 1 + len))
+}
+
+/// Write single message to the writer, prefixed with frame header
+pub fn write_frame<W: Write + ?Sized>(writer: &mut W, msg: &[u8]) -> io::Result<()> {
+    if msg.len() > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Message does not fit into frame",
+        ));
+    }
+    writer.write_all(&(msg.len() as u32).to_le_bytes())?;
+    writer.write_all(msg)?;
+    writer.flush()
+}
+
+/// Read single message from the reader.
+///
+/// Returns `Ok(None)` when reader has no more data before frame header,
+/// if data ends in the middle of frame it results in io::ErrorKind::UnexpectedEof.
+pub fn read_frame<R: Read + ?Sized>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(truncated()),
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    let len = u32::from_le_bytes(header) as usize;
+    // Buffer grows while data arrives, so broken header won't allocate gigabytes upfront
+    let mut msg = Vec::with_capacity(len.min(64 * 1024));
+    reader.take(len as u64).read_to_end(&mut msg)?;
+    if msg.len() < len {
+        return Err(truncated());
+    }
+    Ok(Some(msg))
+}
+
+// Appends utf-8 string prefixed with u32 LE length, used by report and logger encodings
+pub(crate) fn push_field(data: &mut Vec<u8>, field: &str) {
+    data.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    data.extend_from_slice(field.as_bytes());
+}
+
+// Microseconds since UNIX epoch, used by session, logger and trace records
+pub(crate) fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+// Splits utf-8 string prefixed with u32 LE length off the data
+pub(crate) fn split_field(data: &[u8]) -> Option<(&str, &[u8])> {
+    let header = data.get(..HEADER_LEN)?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let field = data.get(HEADER_LEN..HEADER_LEN + len)?;
+    let field = std::str::from_utf8(field).ok()?;
+    Some((field, &data[HEADER_LEN + len..]))
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "Message frame is truncated")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_frame, read_message, write_frame, write_message};
+
+    #[test]
+    fn roundtrip_preserves_boundaries() {
+        let large = vec![7u8; 300_000];
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"first").unwrap();
+        write_frame(&mut stream, &large).unwrap();
+        write_frame(&mut stream, b"").unwrap();
+
+        let mut reader = &stream[..];
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(large));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(vec![]));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"message").unwrap();
+        let mut reader = &stream[..stream.len() - 1];
+        let err = read_frame(&mut reader).expect_err("frame is truncated");
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn channel_messages() {
+        let mut stream = Vec::new();
+        write_message(&mut stream, "", b"default").unwrap();
+        write_message(&mut stream, "progress", b"42%").unwrap();
+
+        let mut reader = &stream[..];
+        assert_eq!(
+            read_message(&mut reader).unwrap(),
+            Some(("".to_string(), b"default".to_vec()))
+        );
+        assert_eq!(
+            read_message(&mut reader).unwrap(),
+            Some(("progress".to_string(), b"42%".to_vec()))
+        );
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+}
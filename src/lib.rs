@@ -2,10 +2,19 @@
 //!  
 //!  # General overview
 //!
-//!  ServiceWorker is a singleton which holds input and output file handles and
-//!  owns worker via Handler trait. Worker is supposedly reactive, usually operating
-//!  on incoming events (on_message) and posting messages to main browser application
-//!  via ServiceWorker::post_message().
+//!  ServiceWorker holds input and output file handles and owns worker via Handler
+//!  trait. Worker is supposedly reactive, usually operating on incoming events
+//!  (on_message) and posting messages to main browser application via
+//!  ServiceWorker::post_message(). Static methods operate on the default worker of
+//!  the thread, native hosts may run independent instances created with ServiceWorker::new.
+//!
+//!  Messages are length-prefixed on both input and output (see [framing]),
+//!  so every Handler::on_message call receives exactly one complete message
+//!  and every ServiceWorker::post_message call arrives as exactly one message.
+//!
+//!  Independent subsystems of the worker can share input and output via named
+//!  channels, see ServiceWorker::channel and ServiceWorker::set_channel_handler.
+//!  Workers which switch modes can stack message handlers, see [stack].
 //!
 //!  # Example usage:
 //!  ```
@@ -13,7 +22,7 @@
 //!
 //!  struct MyWorker {}
 //!  impl Handler for MyWorker {
-//!    fn on_message(&self, msg: &[u8]) -> std::io::Result<()> {
+//!    fn on_message(&self, msg: &[u8]) -> wasi_worker::Result<()> {
 //!      // Process incoming message
 //!      println!("My Worker got message: {:?}", msg);
 //!      Ok(())
@@ -23,8 +32,8 @@
 //!  fn main() {
 //!    // In WASI setup with JS glue all output will be posted to memfs::/output.bin
 //!    // In native OS to be able to run test from shell output goes to ./output.bin
+//!    // Input is read from stdin unless configured otherwise with ServiceOptions::with_input
 //!    let opt = ServiceOptions::default().with_cleanup();
-//!    let output_file = match &opt.output { FileOptions::File(path) => path.clone() };
 //!    ServiceWorker::initialize(opt)
 //!      .expect("ServiceWorker::initialize");
 //!
@@ -36,20 +45,82 @@
 //!      .expect("ServiceWorker.post_message");
 //!  }
 //!  ```
+mod buffer;
+mod channel;
+pub mod codec;
+pub mod control;
+mod error;
+mod executor;
+pub mod framing;
+#[cfg(feature = "log")]
+pub mod logger;
+pub mod report;
+pub mod rpc;
 mod service;
+pub mod session;
+pub mod stack;
+pub mod testing;
+pub mod timer;
+#[cfg(feature = "tracing")]
+pub mod trace;
 
-pub use service::{Handler, ServiceWorker};
+pub use buffer::MemoryBuffer;
+pub use channel::Channel;
+pub use error::{Error, Result};
+pub use executor::LocalFuture;
+pub use service::{AsyncHandler, Handler, HandlerMut, ServiceWorker};
+pub use timer::TimerHandle;
+#[cfg(feature = "macros")]
+pub use wasi_worker_macros::{handler, main, WorkerMessages};
+
+use std::io::Read;
+#[cfg(any(unix, target_os = "wasi"))]
+use std::os::fd::OwnedFd;
 
 /// Instructs on file descriptor configuration for ServiceWorker
 pub enum FileOptions {
+    /// File path, output file is created (truncated) while input file should exist
     File(String),
+    /// Process standard input, valid only for input
+    Stdin,
+    /// Process standard output, valid only for output
+    Stdout,
+    /// Already open file descriptor, ServiceWorker takes ownership and closes it on drop
+    #[cfg(any(unix, target_os = "wasi"))]
+    Fd(OwnedFd),
+    /// In-memory buffer, keep a clone to push or inspect messages
+    Memory(MemoryBuffer),
+    /// Arbitrary reader, valid only for input
+    Reader(Box<dyn Read>),
+    /// Messages are passed to host by pointer and length via `wasi_worker.post_message`
+    /// import without copying to memfs, valid only for output, requires `host-output` feature
+    #[cfg(all(target_os = "wasi", feature = "host-output"))]
+    Host,
+}
+
+/// What to do with incoming message when queue of messages waiting for handler is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message to make room for the new one
+    DropOldest,
+    /// Drop the new message
+    DropNewest,
+    /// Drop the new message and fail ServiceWorker::on_message
+    Error,
 }
 
 /// Options for ServiceWorker
 pub struct ServiceOptions {
-    // TODO:  input: FileOptions,
+    pub input: FileOptions,
     pub cleanup: bool,
     pub output: FileOptions,
+    /// Max number of messages queued until message handler is set
+    pub queue_limit: usize,
+    pub overflow: OverflowPolicy,
+    /// Post handler errors and panics on report::ERROR_CHANNEL instead of trapping
+    pub report_errors: bool,
+    /// Session file where received and posted messages are recorded, see [session]
+    pub record: Option<FileOptions>,
 }
 
 impl ServiceOptions {
@@ -57,17 +128,52 @@ impl ServiceOptions {
         self.cleanup = true;
         self
     }
+
+    pub fn with_input(mut self, input: FileOptions) -> Self {
+        self.input = input;
+        self
+    }
+
+    pub fn with_output(mut self, output: FileOptions) -> Self {
+        self.output = output;
+        self
+    }
+
+    pub fn with_queue(mut self, limit: usize, overflow: OverflowPolicy) -> Self {
+        self.queue_limit = limit;
+        self.overflow = overflow;
+        self
+    }
+
+    /// Handler errors and panics are posted as error reports, see [report].
+    /// Worker keeps running after handler errors.
+    pub fn with_error_reporting(mut self) -> Self {
+        self.report_errors = true;
+        self
+    }
+
+    /// Record every received and posted message to the session file,
+    /// which is kept on cleanup, see [session] for format and replay.
+    pub fn with_recording(mut self, session: FileOptions) -> Self {
+        self.record = Some(session);
+        self
+    }
 }
 
 impl Default for ServiceOptions {
     fn default() -> Self {
         Self {
+            input: FileOptions::Stdin,
             output: if cfg!(target_os = "wasi") {
                 FileOptions::File("/output.bin".to_string())
             } else {
                 FileOptions::File("./output.bin".to_string())
             },
             cleanup: false,
+            queue_limit: 64,
+            overflow: OverflowPolicy::DropOldest,
+            report_errors: false,
+            record: None,
         }
     }
 }
@@ -76,22 +182,203 @@ impl Default for ServiceOptions {
 // To operate it requires JS glue - see wasi-worker-cli
 // Note: It will be substituted by poll_oneoff,
 // though currently poll_oneoff does not transfer control
+#[cfg(feature = "message-ready")]
 #[no_mangle]
 pub extern "C" fn message_ready() -> usize {
-    ServiceWorker::on_message().expect("ServiceWorker.on_message")
+    process_message()
+}
+
+thread_local! {
+  // Frame passed by the host to message_ready_ptr, taken by process_message
+  static READY_FRAME: std::cell::RefCell<Option<Vec<u8>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// What built-in `message_ready` export does: processes one message, returns its length.
+/// Message is the frame passed by the host to `message_ready_ptr` if any, otherwise
+/// it is read from input. Handler errors are posted as error reports in error reporting
+/// mode and result in panic otherwise, see [export_message_ready].
+pub fn process_message() -> usize {
+    if let Some(frame) = READY_FRAME.with(|ready| ready.borrow_mut().take()) {
+        return process_frame(frame);
+    }
+    ServiceWorker::on_message()
+        .or_else(|err| ServiceWorker::report_error(err).map(|_| 0))
+        .expect("ServiceWorker.on_message")
+}
+
+/// Process message frame without length header, i.e.
+/// `[channel name length: u8][channel name][payload]`, returns payload length.
+/// Errors are handled same way as by [process_message].
+pub fn process_frame(frame: Vec<u8>) -> usize {
+    ServiceWorker::on_message_frame(frame)
+        .or_else(|err| ServiceWorker::report_error(err).map(|_| 0))
+        .expect("ServiceWorker.on_message")
+}
+
+/// Generate `message_ready` export calling given function (or closure) which
+/// returns value passed back to the host, [process_message] when omitted.
+///
+/// Zero-copy exports `message_ready_ptr`, `wasi_worker_alloc` and `wasi_worker_dealloc`
+/// are generated as well, `message_ready_ptr` calls the same function, with
+/// [process_message] taking the frame passed by the host. Requires `message-ready`
+/// and `message-ready-ptr` default features to be disabled to avoid duplicate symbols.
+///
+/// Example usage:
+/// ```ignore
+/// use wasi_worker::{export_message_ready, process_message};
+///
+/// export_message_ready!(|| {
+///   let started = std::time::Instant::now();
+///   let len = process_message();
+///   eprintln!("Processed {} bytes in {:?}", len, started.elapsed());
+///   len
+/// });
+/// ```
+#[macro_export]
+macro_rules! export_message_ready {
+    () => {
+        $crate::export_message_ready!($crate::process_message);
+    };
+    ($handler:expr) => {
+        #[no_mangle]
+        pub extern "C" fn message_ready() -> usize {
+            ($handler)()
+        }
+
+        /// # Safety
+        /// See wasi_worker::message_ready_ptr.
+        #[no_mangle]
+        pub unsafe extern "C" fn message_ready_ptr(ptr: *mut u8, len: usize) -> usize {
+            $crate::__set_ready_frame(ptr, len);
+            ($handler)()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn wasi_worker_alloc(len: usize) -> *mut u8 {
+            $crate::__alloc(len)
+        }
+
+        /// # Safety
+        /// See wasi_worker::wasi_worker_dealloc.
+        #[no_mangle]
+        pub unsafe extern "C" fn wasi_worker_dealloc(ptr: *mut u8, len: usize) {
+            $crate::__dealloc(ptr, len)
+        }
+    };
+}
+
+// Used by wasi_worker::main, generates message_ready export unless built-in one is enabled
+#[doc(hidden)]
+#[cfg(feature = "message-ready")]
+#[macro_export]
+macro_rules! __message_ready_export {
+    () => {};
+}
+
+#[doc(hidden)]
+#[cfg(not(feature = "message-ready"))]
+#[macro_export]
+macro_rules! __message_ready_export {
+    () => {
+        $crate::export_message_ready!();
+    };
+}
+
+/// Used by export_message_ready, frame is processed by the next process_message call
+///
+/// # Safety
+/// Same as of message_ready_ptr.
+#[doc(hidden)]
+pub unsafe fn __set_ready_frame(ptr: *mut u8, len: usize) {
+    let frame = take_buffer(ptr, len);
+    READY_FRAME.with(|ready| ready.replace(Some(frame)));
+}
+
+#[doc(hidden)]
+pub fn __alloc(len: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+}
+
+/// # Safety
+/// Same as of wasi_worker_dealloc.
+#[doc(hidden)]
+pub unsafe fn __dealloc(ptr: *mut u8, len: usize) {
+    drop(take_buffer(ptr, len));
+}
+
+unsafe fn take_buffer(ptr: *mut u8, len: usize) -> Vec<u8> {
+    Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)).into_vec()
+}
+
+/// Zero-copy alternative of message_ready: host allocates buffer with
+/// wasi_worker_alloc, writes message frame without length header into it,
+/// i.e. `[channel name length: u8][channel name][payload]`, and passes
+/// buffer ownership back to the worker. Requires `message-ready-ptr` default feature.
+///
+/// # Safety
+/// `ptr` and `len` must describe buffer returned by wasi_worker_alloc,
+/// it must not be used by the host after the call.
+#[cfg(feature = "message-ready-ptr")]
+#[no_mangle]
+pub unsafe extern "C" fn message_ready_ptr(ptr: *mut u8, len: usize) -> usize {
+    process_frame(take_buffer(ptr, len))
+}
+
+/// Allocate buffer of given length in worker linear memory for the host to write message
+#[cfg(feature = "message-ready-ptr")]
+#[no_mangle]
+pub extern "C" fn wasi_worker_alloc(len: usize) -> *mut u8 {
+    __alloc(len)
 }
 
+/// Free buffer allocated with wasi_worker_alloc which was not passed to message_ready_ptr.
+///
+/// # Safety
+/// `ptr` and `len` must describe buffer returned by wasi_worker_alloc.
+#[cfg(feature = "message-ready-ptr")]
+#[no_mangle]
+pub unsafe extern "C" fn wasi_worker_dealloc(ptr: *mut u8, len: usize) {
+    __dealloc(ptr, len)
+}
+
+// Host wakeup, polls futures spawned on worker executor
+// Returns number of futures which are still pending
+// message_ready and timer_fired poll futures themselves, host calls it after
+// _start returns for futures spawned during setup and whenever it wants
+// futures woken outside of worker calls to make progress
+#[no_mangle]
+pub extern "C" fn poll_tasks() -> usize {
+    executor::poll_pending()
+}
+
+// This function will be called from worker.js when timer armed via
+// wasi_worker.set_timer import is due
+#[cfg(all(target_os = "wasi", feature = "host-timers"))]
+#[no_mangle]
+pub extern "C" fn timer_fired(id: u32) {
+    timer::fire(id)
+}
 
 #[cfg(test)]
 mod tests {
-    use super::{FileOptions, ServiceOptions, ServiceWorker};
+    use super::{
+        control, framing, AsyncHandler, Error, FileOptions, Handler, HandlerMut, LocalFuture,
+        MemoryBuffer, OverflowPolicy, ServiceOptions, ServiceWorker,
+    };
+    use std::cell::RefCell;
+    use std::future::poll_fn;
+    use std::rc::Rc;
+    use std::task::{Poll, Waker};
 
     #[test]
     fn cleanup() {
+        std::fs::create_dir_all("./testdata").expect("Create testdata");
         {
             let opt = ServiceOptions {
+                input: FileOptions::Stdin,
                 output: FileOptions::File("./testdata/output.bin".to_string()),
                 cleanup: true,
+                ..Default::default()
             };
             ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
             std::fs::File::open("./testdata/output.bin")
@@ -101,4 +388,772 @@ mod tests {
         std::fs::File::open("./testdata/output.bin")
             .expect_err("/testdata/output.bin should been cleaned up");
     }
+
+    struct Recorder(Rc<RefCell<Vec<Vec<u8>>>>);
+    impl Handler for Recorder {
+        fn on_message(&self, msg: &[u8]) -> crate::Result<()> {
+            self.0.borrow_mut().push(msg.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn input_from_file() {
+        std::fs::create_dir_all("./testdata").expect("Create testdata");
+        let mut fixture = Vec::new();
+        framing::write_message(&mut fixture, "", b"first").unwrap();
+        framing::write_message(&mut fixture, "", b"second").unwrap();
+        std::fs::write("./testdata/input.bin", fixture).expect("Write testdata/input.bin");
+
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::File("./testdata/input.bin".to_string()))
+            .with_output(FileOptions::File("./testdata/input_test.bin".to_string()))
+            .with_cleanup();
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        let received = Rc::new(RefCell::new(Vec::new()));
+        ServiceWorker::set_message_handler(Box::new(Recorder(received.clone())));
+        assert_eq!(ServiceWorker::on_message().unwrap(), 5);
+        assert_eq!(ServiceWorker::on_message().unwrap(), 6);
+        assert_eq!(ServiceWorker::on_message().unwrap(), 0);
+        ServiceWorker::kill();
+
+        assert_eq!(
+            *received.borrow(),
+            vec![b"first".to_vec(), b"second".to_vec()]
+        );
+        std::fs::File::open("./testdata/input.bin")
+            .expect_err("/testdata/input.bin should been cleaned up");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_to_fd() {
+        std::fs::create_dir_all("./testdata").expect("Create testdata");
+        let file = std::fs::File::create("./testdata/fd_output.bin")
+            .expect("Create testdata/fd_output.bin");
+        let opt = ServiceOptions::default().with_output(FileOptions::Fd(file.into()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::post_message(b"fd").expect("ServiceWorker::post_message");
+        ServiceWorker::kill();
+
+        let data = std::fs::read("./testdata/fd_output.bin").expect("Read testdata/fd_output.bin");
+        std::fs::remove_file("./testdata/fd_output.bin").expect("Remove testdata/fd_output.bin");
+        let mut expected = Vec::new();
+        framing::write_message(&mut expected, "", b"fd").unwrap();
+        assert_eq!(data, expected);
+    }
+
+    struct Echo;
+    impl Handler for Echo {
+        fn on_message(&self, msg: &[u8]) -> crate::Result<()> {
+            ServiceWorker::post_message(msg)
+        }
+    }
+
+    #[test]
+    fn memory_channels() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::set_message_handler(Box::new(Echo));
+        input.push_message(b"ping");
+        input.push_message(b"pong");
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        ServiceWorker::kill();
+
+        assert!(input.is_empty());
+        assert_eq!(
+            output.take_messages().unwrap(),
+            vec![b"ping".to_vec(), b"pong".to_vec()]
+        );
+        assert!(output.is_empty());
+    }
+
+    // Counts messages and switches to Echo after the third one
+    struct Counter(u8);
+    impl HandlerMut for Counter {
+        fn on_message(&mut self, _msg: &[u8]) -> crate::Result<()> {
+            self.0 += 1;
+            ServiceWorker::post_message(&[self.0])?;
+            if self.0 == 3 {
+                ServiceWorker::set_message_handler(Box::new(Echo));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mutable_handler() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::set_message_handler_mut(Box::new(Counter(0)));
+        for _ in 0..4 {
+            input.push_message(b"tick");
+            ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        }
+        ServiceWorker::kill();
+
+        assert_eq!(
+            output.messages().unwrap(),
+            vec![vec![1], vec![2], vec![3], b"tick".to_vec()]
+        );
+    }
+
+    #[test]
+    fn early_messages_queued() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()))
+            .with_queue(2, OverflowPolicy::DropOldest);
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        for msg in [b"one", b"two", b"six"].iter() {
+            input.push_message(*msg);
+            ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        }
+        assert!(output.is_empty());
+        ServiceWorker::set_message_handler(Box::new(Echo));
+        ServiceWorker::kill();
+
+        assert_eq!(
+            output.messages().unwrap(),
+            vec![b"two".to_vec(), b"six".to_vec()]
+        );
+    }
+
+    #[test]
+    fn queue_overflow_error() {
+        let input = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(MemoryBuffer::new()))
+            .with_queue(1, OverflowPolicy::Error);
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        input.push_message(b"one");
+        input.push_message(b"two");
+        ServiceWorker::on_message().expect("first message is queued");
+        let err = ServiceWorker::on_message().expect_err("queue is full");
+        assert!(matches!(err, Error::QueueFull));
+        ServiceWorker::kill();
+    }
+
+    #[test]
+    fn error_variants() {
+        ServiceWorker::kill();
+        let err = ServiceWorker::post_message(b"message").expect_err("not initialized");
+        assert!(matches!(err, Error::NotInitialized));
+        let err: std::io::Error = err.into();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+
+        let input = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(MemoryBuffer::new()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        // Channel name length points past the end of the frame
+        framing::write_frame(&mut &input, &[5, b'a']).unwrap();
+        let err = ServiceWorker::on_message().expect_err("malformed frame");
+        assert!(matches!(err, Error::Decode(_)));
+        ServiceWorker::kill();
+    }
+
+    // Reply slot which is awaited by "ask" message and filled by any other message
+    #[derive(Default)]
+    struct Slot {
+        reply: Option<Vec<u8>>,
+        waker: Option<Waker>,
+    }
+    struct Asker(Rc<RefCell<Slot>>);
+    impl AsyncHandler for Asker {
+        fn on_message(&self, msg: Vec<u8>) -> LocalFuture<crate::Result<()>> {
+            let slot = self.0.clone();
+            Box::pin(async move {
+                if msg != b"ask" {
+                    let mut slot = slot.borrow_mut();
+                    slot.reply = Some(msg);
+                    if let Some(waker) = slot.waker.take() {
+                        waker.wake();
+                    }
+                    return Ok(());
+                }
+                let reply = poll_fn(|cx| {
+                    let mut slot = slot.borrow_mut();
+                    match slot.reply.take() {
+                        Some(reply) => Poll::Ready(reply),
+                        None => {
+                            slot.waker = Some(cx.waker().clone());
+                            Poll::Pending
+                        }
+                    }
+                })
+                .await;
+                ServiceWorker::post_message(&reply)
+            })
+        }
+    }
+
+    #[test]
+    fn async_handler() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::set_async_handler(Box::new(Asker(Default::default())));
+        input.push_message(b"ask");
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        assert_eq!(super::poll_tasks(), 1);
+        assert!(output.messages().unwrap().is_empty());
+        input.push_message(b"answer");
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        assert_eq!(super::poll_tasks(), 0);
+        ServiceWorker::kill();
+
+        assert_eq!(output.messages().unwrap(), vec![b"answer".to_vec()]);
+    }
+
+    struct Progress;
+    impl Handler for Progress {
+        fn on_message(&self, msg: &[u8]) -> crate::Result<()> {
+            ServiceWorker::channel("progress")?.post_message(&[msg, b"%"].concat())
+        }
+    }
+
+    #[test]
+    fn named_channels() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        input.push_channel_message("progress", b"10");
+        input.push_message(b"hello");
+        input.push_channel_message("progress", b"20");
+        for _ in 0..3 {
+            ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        }
+        ServiceWorker::set_message_handler(Box::new(Echo));
+        // Progress messages received during setup wait for their handler
+        assert_eq!(output.channel_messages("progress").unwrap().len(), 0);
+        ServiceWorker::set_channel_handler("progress", Box::new(Progress))
+            .expect("ServiceWorker::set_channel_handler");
+        // Worker is set up, unknown channels are not queued
+        input.push_channel_message("unknown", b"lost");
+        let err = ServiceWorker::on_message().expect_err("no handler");
+        assert!(matches!(err, Error::NoHandler(channel) if channel == "unknown"));
+        // Reserved channels are not available to the worker
+        let err = ServiceWorker::channel("$control").expect_err("reserved channel");
+        assert!(matches!(err, Error::ReservedChannel(_)));
+        let err = ServiceWorker::set_channel_handler("$error", Box::new(Echo))
+            .expect_err("reserved channel");
+        assert!(matches!(err, Error::ReservedChannel(_)));
+        // Name length has to fit into frame
+        let err = ServiceWorker::channel(&"a".repeat(256)).expect_err("long channel name");
+        assert!(matches!(err, Error::ChannelNameTooLong(256)));
+        ServiceWorker::channel(&"a".repeat(255)).expect("ServiceWorker::channel");
+        ServiceWorker::kill();
+
+        assert_eq!(output.messages().unwrap(), vec![b"hello".to_vec()]);
+        assert_eq!(
+            output.channel_messages("progress").unwrap(),
+            vec![b"10%".to_vec(), b"20%".to_vec()]
+        );
+    }
+
+    #[cfg(feature = "message-ready-ptr")]
+    #[test]
+    fn message_from_memory() {
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default().with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::set_message_handler(Box::new(Echo));
+        // Host writes frame body right into buffer allocated by worker
+        let frame = b"\x00hello";
+        let ptr = super::wasi_worker_alloc(frame.len());
+        let len = unsafe {
+            std::ptr::copy_nonoverlapping(frame.as_ptr(), ptr, frame.len());
+            super::message_ready_ptr(ptr, frame.len())
+        };
+        assert_eq!(len, 5);
+        let unused = super::wasi_worker_alloc(16);
+        unsafe { super::wasi_worker_dealloc(unused, 16) };
+        ServiceWorker::kill();
+
+        assert_eq!(output.messages().unwrap(), vec![b"hello".to_vec()]);
+    }
+
+    // Records lifecycle events of the handler
+    struct Lifecycle(Rc<RefCell<Vec<String>>>);
+    impl HandlerMut for Lifecycle {
+        fn on_message(&mut self, msg: &[u8]) -> crate::Result<()> {
+            let msg = String::from_utf8_lossy(msg);
+            self.0.borrow_mut().push(format!("message {}", msg));
+            Ok(())
+        }
+        fn on_start(&mut self) -> crate::Result<()> {
+            self.0.borrow_mut().push("start".to_string());
+            Ok(())
+        }
+        fn on_init(&mut self, payload: &[u8]) -> crate::Result<()> {
+            let payload = String::from_utf8_lossy(payload);
+            self.0.borrow_mut().push(format!("init {}", payload));
+            Ok(())
+        }
+        fn on_shutdown(&mut self) -> crate::Result<()> {
+            self.0.borrow_mut().push("shutdown".to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lifecycle_hooks() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        // Init payload arrives before handler is set
+        input.push_channel_message(control::CONTROL_CHANNEL, b"\x01config");
+        input.push_message(b"hello");
+        for _ in 0..2 {
+            ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        }
+        assert!(output
+            .channel_messages(control::CONTROL_CHANNEL)
+            .unwrap()
+            .is_empty());
+        let events = Rc::new(RefCell::new(Vec::new()));
+        ServiceWorker::set_message_handler_mut(Box::new(Lifecycle(events.clone())));
+        ServiceWorker::kill();
+
+        assert_eq!(
+            *events.borrow(),
+            vec!["start", "init config", "message hello", "shutdown"]
+        );
+        assert_eq!(
+            output.channel_messages(control::CONTROL_CHANNEL).unwrap(),
+            vec![vec![control::READY]]
+        );
+    }
+
+    #[test]
+    fn close_gracefully() {
+        std::fs::create_dir_all("./testdata").expect("Create testdata");
+        std::fs::write("./testdata/close_input.bin", b"").expect("Create input");
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::File("./testdata/close_input.bin".to_string()))
+            .with_output(FileOptions::Memory(output.clone()))
+            .with_cleanup();
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        let events = Rc::new(RefCell::new(Vec::new()));
+        ServiceWorker::set_message_handler_mut(Box::new(Lifecycle(events.clone())));
+        ServiceWorker::shutdown(3).expect("ServiceWorker::shutdown");
+
+        assert_eq!(*events.borrow(), vec!["start", "shutdown"]);
+        assert_eq!(
+            output.channel_messages(control::CONTROL_CHANNEL).unwrap(),
+            vec![vec![control::READY], vec![control::CLOSING, 3, 0, 0, 0]]
+        );
+        std::fs::File::open("./testdata/close_input.bin")
+            .expect_err("input should been cleaned up");
+        ServiceWorker::post_message(b"late").expect_err("worker is closed");
+    }
+
+    // Closes on "close" message, posts on shutdown
+    struct Closing;
+    impl Handler for Closing {
+        fn on_message(&self, msg: &[u8]) -> crate::Result<()> {
+            if msg == b"close" {
+                ServiceWorker::close(5);
+            }
+            ServiceWorker::post_message(msg)
+        }
+        fn on_shutdown(&self) -> crate::Result<()> {
+            ServiceWorker::post_message(b"shutdown")
+        }
+    }
+
+    // Process exits, so worker runs in a child process of the test
+    #[test]
+    fn close_from_handler() {
+        const OUTPUT: &str = "./testdata/close_handler_output.bin";
+        if std::env::var_os("CLOSE_FROM_HANDLER").is_some() {
+            let input = MemoryBuffer::new();
+            let opt = ServiceOptions::default()
+                .with_input(FileOptions::Memory(input.clone()))
+                .with_output(FileOptions::File(OUTPUT.to_string()));
+            ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+            ServiceWorker::set_message_handler(Box::new(Closing));
+            input.push_message(b"close");
+            let _ = ServiceWorker::on_message();
+            panic!("worker should have exited");
+        }
+        std::fs::create_dir_all("./testdata").expect("Create testdata");
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "tests::close_from_handler", "--test-threads=1"])
+            .env("CLOSE_FROM_HANDLER", "1")
+            .stdout(std::process::Stdio::null())
+            .status()
+            .expect("Run child");
+        assert_eq!(status.code(), Some(5));
+
+        let mut output = std::fs::File::open(OUTPUT).expect("Open output");
+        let mut messages = Vec::new();
+        while let Some(message) = framing::read_message(&mut output).expect("read_message") {
+            messages.push(message);
+        }
+        std::fs::remove_file(OUTPUT).expect("Remove output");
+        let message = |channel: &str, data: &[u8]| (channel.to_string(), data.to_vec());
+        // Handler finishes its call, then gets on_shutdown
+        assert_eq!(
+            messages,
+            vec![
+                message(control::CONTROL_CHANNEL, &[control::READY]),
+                message("", b"close"),
+                message("", b"shutdown"),
+                message(control::CONTROL_CHANNEL, &[control::CLOSING, 5, 0, 0, 0]),
+            ]
+        );
+    }
+
+    // Built-in exports conflict with the generated ones
+    #[cfg(not(feature = "message-ready"))]
+    mod custom_export {
+        use super::{Echo, FileOptions, MemoryBuffer, ServiceOptions, ServiceWorker};
+        use std::cell::Cell;
+
+        thread_local! {
+          static CALLS: Cell<usize> = const { Cell::new(0) };
+        }
+
+        crate::export_message_ready!(|| {
+            CALLS.with(|calls| calls.set(calls.get() + 1));
+            crate::process_message()
+        });
+
+        #[test]
+        fn custom_message_ready() {
+            let input = MemoryBuffer::new();
+            let output = MemoryBuffer::new();
+            let opt = ServiceOptions::default()
+                .with_input(FileOptions::Memory(input.clone()))
+                .with_output(FileOptions::Memory(output.clone()));
+            ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+            ServiceWorker::set_message_handler(Box::new(Echo));
+            input.push_message(b"hello");
+            assert_eq!(message_ready(), 5);
+            ServiceWorker::kill();
+
+            assert_eq!(CALLS.with(|calls| calls.get()), 1);
+            assert_eq!(output.messages().unwrap(), vec![b"hello".to_vec()]);
+        }
+
+        // Host prefers zero-copy export, it runs custom message_ready too
+        #[test]
+        fn custom_message_ready_ptr() {
+            let output = MemoryBuffer::new();
+            let opt = ServiceOptions::default().with_output(FileOptions::Memory(output.clone()));
+            ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+            ServiceWorker::set_message_handler(Box::new(Echo));
+            // Frame of the default channel: empty channel name and payload
+            let frame = b"\x00hello";
+            let ptr = wasi_worker_alloc(frame.len());
+            let len = unsafe {
+                std::ptr::copy_nonoverlapping(frame.as_ptr(), ptr, frame.len());
+                message_ready_ptr(ptr, frame.len())
+            };
+            assert_eq!(len, 5);
+            unsafe { wasi_worker_dealloc(wasi_worker_alloc(8), 8) };
+            ServiceWorker::kill();
+
+            assert_eq!(CALLS.with(|calls| calls.get()), 1);
+            assert_eq!(output.messages().unwrap(), vec![b"hello".to_vec()]);
+        }
+    }
+
+    // Echoes message and passes it to another worker instance
+    struct Relay(Rc<ServiceWorker>);
+    impl Handler for Relay {
+        fn on_message(&self, msg: &[u8]) -> crate::Result<()> {
+            ServiceWorker::post_message(msg)?;
+            self.0.dispatch(msg)
+        }
+    }
+
+    #[test]
+    fn worker_instances() {
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default().with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::set_message_handler(Box::new(Echo));
+
+        let output_a = MemoryBuffer::new();
+        let output_b = MemoryBuffer::new();
+        let opt = ServiceOptions::default().with_output(FileOptions::Memory(output_b.clone()));
+        let b = Rc::new(ServiceWorker::new(opt).expect("ServiceWorker::new"));
+        b.set_handler(Box::new(Echo));
+        let opt = ServiceOptions::default().with_output(FileOptions::Memory(output_a.clone()));
+        let a = ServiceWorker::new(opt).expect("ServiceWorker::new");
+        a.set_handler(Box::new(Relay(b.clone())));
+
+        a.dispatch(b"one").expect("ServiceWorker::dispatch");
+        b.post(b"two").expect("ServiceWorker::post");
+        ServiceWorker::post_message(b"default").expect("ServiceWorker::post_message");
+        // Timers and virtual time belong to the instance
+        b.enter(|| {
+            ServiceWorker::set_timeout(std::time::Duration::from_millis(10), || {
+                ServiceWorker::post_message(b"tick").expect("ServiceWorker::post_message");
+            })
+        });
+        crate::timer::advance(std::time::Duration::from_millis(10));
+        assert_eq!(b.enter(crate::timer::now), std::time::Duration::ZERO);
+        b.enter(|| crate::timer::advance(std::time::Duration::from_millis(10)));
+
+        assert_eq!(output_a.messages().unwrap(), vec![b"one".to_vec()]);
+        assert_eq!(
+            output_b.messages().unwrap(),
+            vec![b"one".to_vec(), b"two".to_vec(), b"tick".to_vec()]
+        );
+        assert_eq!(output.messages().unwrap(), vec![b"default".to_vec()]);
+        drop(a);
+        drop(b);
+        // Default worker is still running
+        ServiceWorker::post_message(b"after").expect("ServiceWorker::post_message");
+        ServiceWorker::kill();
+        assert_eq!(
+            output.messages().unwrap(),
+            vec![b"default".to_vec(), b"after".to_vec()]
+        );
+    }
+
+    // Calls back into the worker from its on_message
+    struct Reentrant {
+        input: MemoryBuffer,
+        events: Rc<RefCell<Vec<String>>>,
+    }
+    impl HandlerMut for Reentrant {
+        fn on_message(&mut self, msg: &[u8]) -> crate::Result<()> {
+            let msg = String::from_utf8_lossy(msg).to_string();
+            self.events.borrow_mut().push(format!("message {}", msg));
+            match msg.as_str() {
+                "nested" => {
+                    // Channel is busy, message is delivered after this call
+                    self.input.push_message(b"inner");
+                    assert_eq!(ServiceWorker::on_message()?, 5);
+                }
+                "swap" => ServiceWorker::set_message_handler(Box::new(Echo)),
+                "kill" => ServiceWorker::kill(),
+                _ => (),
+            }
+            self.events.borrow_mut().push(format!("done {}", msg));
+            Ok(())
+        }
+        fn on_shutdown(&mut self) -> crate::Result<()> {
+            self.events.borrow_mut().push("shutdown".to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reentrant_handler() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let options = || {
+            ServiceOptions::default()
+                .with_input(FileOptions::Memory(input.clone()))
+                .with_output(FileOptions::Memory(output.clone()))
+        };
+        ServiceWorker::initialize(options()).expect("ServiceWorker::initialize");
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let handler = |events: &Rc<RefCell<Vec<String>>>| {
+            Box::new(Reentrant {
+                input: input.clone(),
+                events: events.clone(),
+            })
+        };
+        ServiceWorker::set_message_handler_mut(handler(&events));
+        for msg in [&b"nested"[..], b"swap", b"echo"] {
+            input.push_message(msg);
+            ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        }
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                "message nested",
+                "done nested",
+                "message inner",
+                "done inner",
+                "message swap",
+                "done swap",
+            ]
+        );
+        assert_eq!(output.messages().unwrap(), vec![b"echo".to_vec()]);
+        ServiceWorker::kill();
+
+        // Killed worker does not get its handler back
+        ServiceWorker::initialize(options()).expect("ServiceWorker::initialize");
+        let events = Rc::new(RefCell::new(Vec::new()));
+        ServiceWorker::set_message_handler_mut(handler(&events));
+        input.push_message(b"kill");
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        assert_eq!(
+            *events.borrow(),
+            vec!["message kill", "done kill", "shutdown"]
+        );
+        assert!(matches!(
+            ServiceWorker::post_message(b"late"),
+            Err(Error::NotInitialized)
+        ));
+    }
+
+    // Progress handler which dispatches nested message of its own channel
+    struct NestedProgress {
+        input: MemoryBuffer,
+        events: Rc<RefCell<Vec<String>>>,
+    }
+    impl HandlerMut for NestedProgress {
+        fn on_message(&mut self, msg: &[u8]) -> crate::Result<()> {
+            let msg = String::from_utf8_lossy(msg).to_string();
+            self.events.borrow_mut().push(format!("message {}", msg));
+            if msg == "10%" {
+                // Channel is busy, message is delivered after this call
+                self.input.push_channel_message("progress", b"20%");
+                assert_eq!(ServiceWorker::on_message()?, 3);
+            }
+            self.events.borrow_mut().push(format!("done {}", msg));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reentrant_channel() {
+        let input = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(MemoryBuffer::new()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::set_message_handler(Box::new(Echo));
+        let events = Rc::new(RefCell::new(Vec::new()));
+        ServiceWorker::set_channel_handler_mut(
+            "progress",
+            Box::new(NestedProgress {
+                input: input.clone(),
+                events: events.clone(),
+            }),
+        )
+        .expect("ServiceWorker::set_channel_handler_mut");
+        input.push_channel_message("progress", b"10%");
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        ServiceWorker::kill();
+
+        assert_eq!(
+            *events.borrow(),
+            vec!["message 10%", "done 10%", "message 20%", "done 20%"]
+        );
+    }
+
+    // Panics on "panic", echoes other messages
+    struct Panicking;
+    impl Handler for Panicking {
+        fn on_message(&self, msg: &[u8]) -> crate::Result<()> {
+            if msg == b"panic" {
+                panic!("handler panics");
+            }
+            ServiceWorker::post_message(msg)
+        }
+    }
+
+    #[test]
+    fn handler_survives_panic() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::set_message_handler(Box::new(Panicking));
+        input.push_message(b"panic");
+        std::panic::catch_unwind(ServiceWorker::on_message).expect_err("handler panics");
+        // Handler is back in its slot
+        input.push_message(b"hello");
+        assert_eq!(
+            ServiceWorker::on_message().expect("ServiceWorker::on_message"),
+            5
+        );
+        ServiceWorker::kill();
+
+        assert_eq!(output.messages().unwrap(), vec![b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn reentrant_instance() {
+        // Handler of b dispatches back to a, which is busy relaying to b
+        struct Back(Rc<RefCell<Option<Rc<ServiceWorker>>>>);
+        impl Handler for Back {
+            fn on_message(&self, msg: &[u8]) -> crate::Result<()> {
+                let a = self.0.borrow().clone().expect("worker a");
+                let result = a.dispatch(msg);
+                assert!(matches!(result, Err(Error::Reentrant)));
+                assert!(matches!(a.post(msg), Err(Error::Reentrant)));
+                ServiceWorker::post_message(msg)
+            }
+        }
+        let output_b = MemoryBuffer::new();
+        let opt = ServiceOptions::default().with_output(FileOptions::Memory(output_b.clone()));
+        let b = Rc::new(ServiceWorker::new(opt).expect("ServiceWorker::new"));
+        let back = Rc::new(RefCell::new(None));
+        b.set_handler(Box::new(Back(back.clone())));
+        let opt = ServiceOptions::default().with_output(FileOptions::Memory(MemoryBuffer::new()));
+        let a = Rc::new(ServiceWorker::new(opt).expect("ServiceWorker::new"));
+        a.set_handler(Box::new(Relay(b.clone())));
+        back.replace(Some(a.clone()));
+
+        a.dispatch(b"ping").expect("ServiceWorker::dispatch");
+        assert_eq!(output_b.messages().unwrap(), vec![b"ping".to_vec()]);
+        back.replace(None);
+    }
+
+    #[test]
+    fn reentrant_tasks() {
+        let output = MemoryBuffer::new();
+        // Nested on_message must not block on stdin
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(MemoryBuffer::new()))
+            .with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        // Task wakes itself and runs nested poll before it yields
+        let mut polled = false;
+        ServiceWorker::spawn_local(poll_fn(move |cx| {
+            if polled {
+                ServiceWorker::post_message(b"woken").expect("ServiceWorker::post_message");
+                return Poll::Ready(());
+            }
+            polled = true;
+            cx.waker().wake_by_ref();
+            ServiceWorker::on_message().expect("ServiceWorker::on_message");
+            Poll::Pending
+        }));
+        assert_eq!(super::poll_tasks(), 0);
+        assert_eq!(output.messages().unwrap(), vec![b"woken".to_vec()]);
+
+        // Task kills the worker, it is dropped once it yields
+        ServiceWorker::spawn_local(poll_fn(|_| {
+            ServiceWorker::kill();
+            Poll::<()>::Pending
+        }));
+        assert_eq!(super::poll_tasks(), 0);
+    }
 }
@@ -0,0 +1,197 @@
+//! `log` crate backend which forwards records to the host, requires `log` feature.
+//!
+//! Records are posted on the reserved `$log` channel:
+//! `[level: u8][timestamp: u64 LE][target][module path][message]`, where level is
+//! 1 (error) to 5 (trace), timestamp is microseconds since UNIX epoch and strings are
+//! prefixed with u32 LE length, module path is empty when not available.
+//!
+//! Main application changes max level at runtime by sending single byte
+//! message to the `$log` channel: 0 (off) to 5 (trace).
+//!
+//! While ServiceWorker is not initialized records are written to stderr,
+//! which allows to use the same logger in native builds and tests.
+//!
+//! Example usage:
+//! ```
+//! use wasi_worker::logger;
+//!
+//! logger::init(log::LevelFilter::Info).expect("logger::init");
+//! log::info!("worker started");
+//! ```
+use super::framing::{push_field, split_field, timestamp};
+use super::{Error, Handler, Result, ServiceWorker};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Channel of log records and level control messages
+pub const LOG_CHANNEL: &str = "$log";
+
+static LOGGER: WorkerLogger = WorkerLogger;
+// Logger is set once per process, level control is attached to every initialized worker
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Install worker logger with initial max level, level control messages are
+/// handled by current worker and by workers initialized afterwards.
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(level);
+    INSTALLED.store(true, Ordering::Relaxed);
+    attach();
+    Ok(())
+}
+
+// Handles level control messages of current worker if logger is installed,
+// called by ServiceWorker::initialize
+pub(crate) fn attach() {
+    if INSTALLED.load(Ordering::Relaxed) {
+        ServiceWorker::set_reserved_handler(LOG_CHANNEL, Box::new(LevelControl));
+    }
+}
+
+/// Logger which posts records on LOG_CHANNEL, see [init]
+pub struct WorkerLogger;
+
+impl Log for WorkerLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let record = LogRecord {
+            level: record.level(),
+            timestamp: timestamp(),
+            target: record.target().to_string(),
+            module_path: record.module_path().map(|path| path.to_string()),
+            message: record.args().to_string(),
+        };
+        if ServiceWorker::post_channel_message(LOG_CHANNEL, &encode_record(&record)).is_err() {
+            eprintln!("[{} {}] {}", record.level, record.target, record.message);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Sets max level from level control message
+struct LevelControl;
+
+impl Handler for LevelControl {
+    fn on_message(&self, msg: &[u8]) -> Result<()> {
+        let level = match msg {
+            [0] => LevelFilter::Off,
+            [1] => LevelFilter::Error,
+            [2] => LevelFilter::Warn,
+            [3] => LevelFilter::Info,
+            [4] => LevelFilter::Debug,
+            [5] => LevelFilter::Trace,
+            _ => return Err(Error::Decode("Malformed log level".to_string())),
+        };
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+/// Decoded log record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogRecord {
+    pub level: Level,
+    /// Microseconds since UNIX epoch
+    pub timestamp: u64,
+    pub target: String,
+    pub module_path: Option<String>,
+    pub message: String,
+}
+
+pub fn encode_record(record: &LogRecord) -> Vec<u8> {
+    let mut data = vec![record.level as u8];
+    data.extend_from_slice(&record.timestamp.to_le_bytes());
+    push_field(&mut data, &record.target);
+    push_field(&mut data, record.module_path.as_deref().unwrap_or(""));
+    push_field(&mut data, &record.message);
+    data
+}
+
+pub fn decode_record(data: &[u8]) -> Result<LogRecord> {
+    if data.len() < 9 {
+        return Err(malformed());
+    }
+    let level = match data[0] {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        5 => Level::Trace,
+        _ => return Err(malformed()),
+    };
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&data[1..9]);
+    let (target, rest) = split_field(&data[9..]).ok_or_else(malformed)?;
+    let (module_path, rest) = split_field(rest).ok_or_else(malformed)?;
+    let (message, _) = split_field(rest).ok_or_else(malformed)?;
+    Ok(LogRecord {
+        level,
+        timestamp: u64::from_le_bytes(timestamp),
+        target: target.to_string(),
+        module_path: Some(module_path.to_string()).filter(|path| !path.is_empty()),
+        message: message.to_string(),
+    })
+}
+
+fn malformed() -> Error {
+    Error::Decode("Malformed log record".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileOptions, MemoryBuffer, ServiceOptions};
+
+    #[test]
+    fn forwards_records() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        init(LevelFilter::Info).expect("logger::init");
+        log::debug!("filtered out");
+        log::info!(target: "stage", "processed {} tiles", 3);
+        // Main application enables debug records
+        input.push_channel_message(LOG_CHANNEL, &[4]);
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        log::debug!("enabled");
+        ServiceWorker::kill();
+
+        let records: Vec<LogRecord> = output
+            .channel_messages(LOG_CHANNEL)
+            .unwrap()
+            .iter()
+            .map(|data| decode_record(data).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].level, Level::Info);
+        assert_eq!(records[0].target, "stage");
+        assert_eq!(records[0].message, "processed 3 tiles");
+        assert_eq!(
+            records[0].module_path.as_deref(),
+            Some("wasi_worker::logger::tests")
+        );
+        assert!(records[0].timestamp > 0);
+        assert_eq!(records[1].level, Level::Debug);
+        assert_eq!(records[1].message, "enabled");
+
+        // Level control is attached to re-initialized worker
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(MemoryBuffer::new()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        input.push_channel_message(LOG_CHANNEL, &[1]);
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        ServiceWorker::kill();
+        assert_eq!(log::max_level(), LevelFilter::Error);
+    }
+}
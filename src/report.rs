@@ -0,0 +1,183 @@
+//! Error reports posted to main application in error reporting mode,
+//! see ServiceOptions::with_error_reporting.
+//!
+//! Reports are posted on the reserved `$error` channel:
+//! `[kind: u8][message length: u32 LE][message][location length: u32 LE][location][backtrace length: u32 LE][backtrace]`,
+//! where kind is 0 for error returned by handler, worker keeps running after it,
+//! and 1 for panic, which traps the instance in WASI target.
+//! Location and backtrace are empty when not available.
+use super::framing::{push_field, split_field};
+use super::{Error, Result, ServiceWorker};
+use std::any::Any;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::panic::Location;
+use std::sync::Once;
+
+/// Channel of error reports
+pub const ERROR_CHANNEL: &str = "$error";
+
+const ERROR: u8 = 0;
+const PANIC: u8 = 1;
+
+/// What caused the report
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportKind {
+    /// Error returned by handler, worker keeps running
+    Error,
+    /// Panic, worker may not recover from it
+    Panic,
+}
+
+/// Decoded error report
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorReport {
+    pub kind: ReportKind,
+    pub message: String,
+    /// Source location of the panic
+    pub location: Option<String>,
+    /// Captured when enabled via RUST_BACKTRACE and supported by the target
+    pub backtrace: Option<String>,
+}
+
+impl From<&Error> for ErrorReport {
+    fn from(err: &Error) -> Self {
+        Self {
+            kind: ReportKind::Error,
+            message: err.to_string(),
+            location: None,
+            backtrace: None,
+        }
+    }
+}
+
+pub fn encode_report(report: &ErrorReport) -> Vec<u8> {
+    let kind = match report.kind {
+        ReportKind::Error => ERROR,
+        ReportKind::Panic => PANIC,
+    };
+    let mut data = vec![kind];
+    for field in [
+        report.message.as_str(),
+        report.location.as_deref().unwrap_or(""),
+        report.backtrace.as_deref().unwrap_or(""),
+    ] {
+        push_field(&mut data, field);
+    }
+    data
+}
+
+pub fn decode_report(data: &[u8]) -> Result<ErrorReport> {
+    let (kind, mut rest) = data.split_first().ok_or_else(malformed)?;
+    let kind = match *kind {
+        ERROR => ReportKind::Error,
+        PANIC => ReportKind::Panic,
+        _ => return Err(malformed()),
+    };
+    let mut fields = Vec::with_capacity(3);
+    for _ in 0..3 {
+        let (field, tail) = split_field(rest).ok_or_else(malformed)?;
+        fields.push(field);
+        rest = tail;
+    }
+    let optional = |field: &str| Some(field.to_string()).filter(|field| !field.is_empty());
+    Ok(ErrorReport {
+        kind,
+        message: fields[0].to_string(),
+        location: optional(fields[1]),
+        backtrace: optional(fields[2]),
+    })
+}
+
+fn malformed() -> Error {
+    Error::Decode("Malformed error report".to_string())
+}
+
+/// Install panic hook which posts panics as error reports when reporting is enabled,
+/// previously installed hook is still called.
+pub(crate) fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if ServiceWorker::is_reporting_errors() {
+                let report = panic_report(info.payload(), info.location());
+                // Service is not available if panic happened while it was in use
+                let _ = ServiceWorker::post_channel_message(ERROR_CHANNEL, &encode_report(&report));
+            }
+            previous(info);
+        }));
+    });
+}
+
+fn panic_report(payload: &(dyn Any + Send), location: Option<&Location>) -> ErrorReport {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|msg| msg.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string());
+    let backtrace = Backtrace::capture();
+    ErrorReport {
+        kind: ReportKind::Panic,
+        message,
+        location: location.map(|location| location.to_string()),
+        backtrace: match backtrace.status() {
+            BacktraceStatus::Captured => Some(backtrace.to_string()),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileOptions, Handler, MemoryBuffer, ServiceOptions};
+
+    struct Failing;
+    impl Handler for Failing {
+        fn on_message(&self, msg: &[u8]) -> Result<()> {
+            if msg == b"panic" {
+                panic!("handler panicked");
+            }
+            Err(Error::Decode("bad message".to_string()))
+        }
+    }
+
+    #[test]
+    fn reports_errors_and_panics() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()))
+            .with_error_reporting();
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::set_message_handler(Box::new(Failing));
+        input.push_message(b"error");
+        input.push_message(b"panic");
+        // Worker keeps running after handler error
+        assert_eq!(crate::process_message(), 0);
+        // Exported functions can't unwind, call ServiceWorker directly
+        std::panic::catch_unwind(ServiceWorker::on_message).expect_err("handler panics");
+        ServiceWorker::kill();
+
+        let reports: Vec<ErrorReport> = output
+            .channel_messages(ERROR_CHANNEL)
+            .unwrap()
+            .iter()
+            .map(|data| decode_report(data).unwrap())
+            .collect();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(
+            reports[0],
+            ErrorReport {
+                kind: ReportKind::Error,
+                message: "Malformed message: bad message".to_string(),
+                location: None,
+                backtrace: None,
+            }
+        );
+        assert_eq!(reports[1].kind, ReportKind::Panic);
+        assert_eq!(reports[1].message, "handler panicked");
+        assert!(reports[1].location.as_ref().unwrap().contains("report.rs"));
+    }
+}
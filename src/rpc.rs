@@ -0,0 +1,345 @@
+//! Request/response layer with correlation ids on top of ServiceWorker messages.
+//!
+//! Request message: `[0u8][id: u32 LE][method name length: u8][method name][params]`
+//!
+//! Response message: `[kind: u8][id: u32 LE][payload]`, where kind is one of
+//! * 1 - reply, final response with payload
+//! * 2 - partial reply, more responses with the same id will follow
+//! * 3 - end of partial replies, empty payload
+//! * 4 - error, final response with utf-8 error description as payload
+//!
+//! Example usage:
+//! ```
+//! use wasi_worker::rpc::{Replies, Rpc};
+//! use wasi_worker::ServiceWorker;
+//!
+//! let mut rpc = Rpc::new();
+//! // Single reply per request
+//! rpc.method("echo", |params| Ok(params.to_vec()));
+//! // Many replies per request
+//! rpc.stream("count", |params, replies: &mut Replies| {
+//!   for i in 0..params.len() as u8 {
+//!     replies.send(&[i])?;
+//!   }
+//!   Ok(())
+//! });
+//! ServiceWorker::set_message_handler(Box::new(rpc));
+//!
+//! // Replies are posted to the channel of the handler, e.g. next to yew agent
+//! // which owns the message channel
+//! let mut rpc = Rpc::on_channel("rpc").expect("Rpc::on_channel");
+//! rpc.method("echo", |params| Ok(params.to_vec()));
+//! ServiceWorker::set_channel_handler("rpc", Box::new(rpc)).expect("set_channel_handler");
+//! ```
+use super::framing::DEFAULT_CHANNEL;
+use super::{Channel, Error, Handler, Result, ServiceWorker};
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+const REQUEST: u8 = 0;
+const REPLY: u8 = 1;
+const PARTIAL: u8 = 2;
+const END: u8 = 3;
+const ERROR: u8 = 4;
+
+type Method = Box<dyn Fn(&[u8], &mut Replies) -> Result<()>>;
+
+/// Handler which routes requests to registered methods and posts their replies
+pub struct Rpc {
+    methods: HashMap<String, Method>,
+    // Channel replies are posted to
+    channel: Channel,
+}
+
+impl Default for Rpc {
+    fn default() -> Self {
+        Self {
+            methods: HashMap::new(),
+            channel: Channel::new(DEFAULT_CHANNEL),
+        }
+    }
+}
+
+/// Sends replies for the request being processed
+pub struct Replies {
+    id: u32,
+    channel: Channel,
+}
+
+impl Replies {
+    /// Post partial reply, request is completed when method returns
+    pub fn send(&mut self, payload: &[u8]) -> Result<()> {
+        self.post(&Response::Partial(self.id, payload))
+    }
+
+    fn post(&self, response: &Response) -> Result<()> {
+        self.channel.post_message(&encode_response(response))
+    }
+
+    /// Correlation id of the request
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Rpc {
+    /// Rpc handler for the message channel
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rpc handler posting replies to the named channel, it is expected
+    /// to be set as handler of the same channel, see ServiceWorker::set_channel_handler
+    pub fn on_channel(name: &str) -> Result<Self> {
+        Ok(Self {
+            methods: HashMap::new(),
+            channel: ServiceWorker::channel(name)?,
+        })
+    }
+
+    /// Register method with single reply, returned data is posted as reply
+    /// and error is posted as error response.
+    pub fn method<F>(&mut self, name: &str, method: F) -> &mut Self
+    where
+        F: Fn(&[u8]) -> Result<Vec<u8>> + 'static,
+    {
+        let method = move |params: &[u8], replies: &mut Replies| {
+            let reply = method(params)?;
+            replies.post(&Response::Reply(replies.id, &reply))
+        };
+        self.methods.insert(name.to_string(), Box::new(method));
+        self
+    }
+
+    /// Register method with many replies posted via Replies::send,
+    /// end response is posted when method returns.
+    pub fn stream<F>(&mut self, name: &str, method: F) -> &mut Self
+    where
+        F: Fn(&[u8], &mut Replies) -> Result<()> + 'static,
+    {
+        let method = move |params: &[u8], replies: &mut Replies| {
+            method(params, replies)?;
+            replies.post(&Response::End(replies.id))
+        };
+        self.methods.insert(name.to_string(), Box::new(method));
+        self
+    }
+}
+
+impl Handler for Rpc {
+    /// Malformed request results in Error::Decode,
+    /// errors of methods are posted back as error responses.
+    fn on_message(&self, msg: &[u8]) -> Result<()> {
+        let request = decode_request(msg)?;
+        let mut replies = Replies {
+            id: request.id,
+            channel: self.channel.clone(),
+        };
+        let result = match self.methods.get(request.method) {
+            Some(method) => method(request.params, &mut replies),
+            None => Err(Error::NoMethod(request.method.to_string())),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                let description = err.to_string();
+                replies.post(&Response::Error(request.id, &description))
+            }
+        }
+    }
+}
+
+/// Decoded request
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub id: u32,
+    pub method: &'a str,
+    pub params: &'a [u8],
+}
+
+/// Decoded response
+#[derive(Debug, PartialEq, Eq)]
+pub enum Response<'a> {
+    Reply(u32, &'a [u8]),
+    Partial(u32, &'a [u8]),
+    End(u32),
+    Error(u32, &'a str),
+}
+
+impl Response<'_> {
+    /// Correlation id of the request
+    pub fn id(&self) -> u32 {
+        match self {
+            Response::Reply(id, _)
+            | Response::Partial(id, _)
+            | Response::End(id)
+            | Response::Error(id, _) => *id,
+        }
+    }
+
+    /// Whether this is the last response for the request
+    pub fn is_final(&self) -> bool {
+        !matches!(self, Response::Partial(..))
+    }
+}
+
+/// Method name is limited to 255 bytes, longer name results in Error::Decode
+pub fn encode_request(request: &Request) -> Result<Vec<u8>> {
+    let name = request.method.as_bytes();
+    if name.len() > u8::MAX as usize {
+        return Err(Error::Decode("Rpc method name is too long".to_string()));
+    }
+    let mut data = Vec::with_capacity(6 + name.len() + request.params.len());
+    data.push(REQUEST);
+    data.extend_from_slice(&request.id.to_le_bytes());
+    data.push(name.len() as u8);
+    data.extend_from_slice(name);
+    data.extend_from_slice(request.params);
+    Ok(data)
+}
+
+pub fn decode_request(data: &[u8]) -> Result<Request<'_>> {
+    let (kind, id, rest) = split_header(data)?;
+    if kind != REQUEST || rest.is_empty() {
+        return Err(malformed());
+    }
+    let len = rest[0] as usize;
+    if rest.len() < 1 + len {
+        return Err(malformed());
+    }
+    let method = std::str::from_utf8(&rest[1..1 + len]).map_err(|_| malformed())?;
+    Ok(Request {
+        id,
+        method,
+        params: &rest[1 + len..],
+    })
+}
+
+pub fn encode_response(response: &Response) -> Vec<u8> {
+    let (kind, payload) = match response {
+        Response::Reply(_, payload) => (REPLY, *payload),
+        Response::Partial(_, payload) => (PARTIAL, *payload),
+        Response::End(_) => (END, &[][..]),
+        Response::Error(_, description) => (ERROR, description.as_bytes()),
+    };
+    let mut data = Vec::with_capacity(5 + payload.len());
+    data.push(kind);
+    data.extend_from_slice(&response.id().to_le_bytes());
+    data.extend_from_slice(payload);
+    data
+}
+
+pub fn decode_response(data: &[u8]) -> Result<Response<'_>> {
+    let (kind, id, payload) = split_header(data)?;
+    match kind {
+        REPLY => Ok(Response::Reply(id, payload)),
+        PARTIAL => Ok(Response::Partial(id, payload)),
+        END => Ok(Response::End(id)),
+        ERROR => Ok(Response::Error(
+            id,
+            std::str::from_utf8(payload).map_err(|_| malformed())?,
+        )),
+        _ => Err(malformed()),
+    }
+}
+
+fn split_header(data: &[u8]) -> Result<(u8, u32, &[u8])> {
+    if data.len() < 5 {
+        return Err(malformed());
+    }
+    let id = u32::from_le_bytes(data[1..5].try_into().map_err(|_| malformed())?);
+    Ok((data[0], id, &data[5..]))
+}
+
+fn malformed() -> Error {
+    Error::Decode("Malformed rpc message".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockHost;
+    use crate::{FileOptions, MemoryBuffer, ServiceOptions};
+
+    fn call(rpc: &Rpc, id: u32, method: &str, params: &[u8]) {
+        let request = encode_request(&Request { id, method, params }).unwrap();
+        rpc.on_message(&request).expect("Rpc::on_message");
+    }
+
+    #[test]
+    fn routes_replies() {
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default().with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        let mut rpc = Rpc::new();
+        rpc.method("echo", |params| Ok(params.to_vec()))
+            .method("fail", |_| Err(std::io::Error::other("failed").into()))
+            .stream("count", |params, replies| {
+                for i in 0..params[0] {
+                    replies.send(&[i])?;
+                }
+                Ok(())
+            });
+        call(&rpc, 1, "echo", b"hello");
+        call(&rpc, 2, "count", &[2]);
+        call(&rpc, 3, "fail", b"");
+        call(&rpc, 4, "missing", b"");
+        rpc.on_message(b"garbage")
+            .expect_err("malformed request is an error");
+        ServiceWorker::kill();
+
+        let messages = output.messages().unwrap();
+        let responses: Vec<Response> = messages
+            .iter()
+            .map(|msg| decode_response(msg).unwrap())
+            .collect();
+        assert_eq!(
+            responses,
+            vec![
+                Response::Reply(1, b"hello"),
+                Response::Partial(2, &[0]),
+                Response::Partial(2, &[1]),
+                Response::End(2),
+                Response::Error(3, "failed"),
+                Response::Error(4, "Unknown method missing"),
+            ]
+        );
+    }
+
+    #[test]
+    fn replies_on_channel() {
+        let host = MockHost::new().expect("MockHost::new");
+        let mut rpc = Rpc::on_channel("rpc").expect("Rpc::on_channel");
+        rpc.method("echo", |params| Ok(params.to_vec()))
+            .stream("count", |params, replies| {
+                for i in 0..params[0] {
+                    replies.send(&[i])?;
+                }
+                Ok(())
+            });
+        ServiceWorker::set_channel_handler("rpc", Box::new(rpc)).expect("set_channel_handler");
+        let request = |id, method, params| encode_request(&Request { id, method, params }).unwrap();
+        host.send_channel("rpc", &request(1, "echo", b"hi"))
+            .expect("MockHost::send_channel");
+        host.send_channel("rpc", &request(2, "count", &[1]))
+            .expect("MockHost::send_channel");
+        host.send_channel("rpc", &request(3, "missing", b""))
+            .expect("MockHost::send_channel");
+
+        assert!(host.posted().is_empty());
+        let messages = host.posted_on("rpc");
+        let responses: Vec<Response> = messages
+            .iter()
+            .map(|msg| decode_response(msg).unwrap())
+            .collect();
+        assert_eq!(
+            responses,
+            vec![
+                Response::Reply(1, b"hi"),
+                Response::Partial(2, &[0]),
+                Response::End(2),
+                Response::Error(3, "Unknown method missing"),
+            ]
+        );
+    }
+}
@@ -1,122 +1,1035 @@
-use super::{FileOptions, ServiceOptions};
-use std::cell::RefCell;
+use super::channel::{self, Channel};
+use super::codec::Codec;
+use super::control::{self, CONTROL_CHANNEL};
+use super::executor::{self, LocalFuture};
+use super::framing::{read_frame, split_channel, write_message, DEFAULT_CHANNEL};
+use super::report::{self, encode_report, ErrorReport, ERROR_CHANNEL};
+use super::session::{Direction, Recorder};
+use super::stack::{self, StackHandler};
+use super::timer::{self, TimerHandle};
+use super::{Error, FileOptions, OverflowPolicy, Result, ServiceOptions};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::time::Duration;
 
 /// Connects Rust Handler with browser service worker via WASI filesystem.
 ///
-/// ServiceWorker is a singleton which holds input and output file handles and
-/// owns woker via Handler trait. Worker is supposedly reactive, usually operating
-/// on incoming events (on_message) and posting messages to main browser application
-/// via ServiceWorker::post_message().
+/// ServiceWorker holds input and output file handles and owns woker via Handler trait.
+/// Worker is supposedly reactive, usually operating on incoming events (on_message)
+/// and posting messages to main browser application via ServiceWorker::post_message().
+///
+/// Static methods operate on the default worker of the current thread, which is
+/// what exported functions (message_ready, etc.) use. Independent workers are created
+/// with ServiceWorker::new, static methods called while instance is entered
+/// (including calls from its handlers, timers and futures) operate on that instance.
+///
+/// Example usage:
+/// ```
+/// use wasi_worker::{FileOptions, Handler, MemoryBuffer, ServiceOptions, ServiceWorker};
+///
+/// struct Echo;
+/// impl Handler for Echo {
+///   fn on_message(&self, msg: &[u8]) -> wasi_worker::Result<()> {
+///     ServiceWorker::post_message(msg)
+///   }
+/// }
+///
+/// let output = MemoryBuffer::new();
+/// let opt = ServiceOptions::default().with_output(FileOptions::Memory(output.clone()));
+/// let sw = ServiceWorker::new(opt).expect("ServiceWorker::new");
+/// sw.set_handler(Box::new(Echo));
+/// sw.dispatch(b"ping").expect("ServiceWorker::dispatch");
+/// assert_eq!(output.messages().unwrap(), vec![b"ping".to_vec()]);
+/// ```
 ///
 /// Note: ServiceWorker supposed to operate in single threaded environment
 /// like a browser service worker.
-///
-/// TODO: it requires cleaning of filesystem, add drop implementation
 pub struct ServiceWorker {
-    output: File,
-    input: io::Stdin,
-    options: ServiceOptions,
+    // State of the worker while it is not entered, see ServiceWorker::enter
+    state: RefCell<State>,
+    entered: Cell<bool>,
+}
+
+// Thread local state of the worker, instance state is swapped in while it is entered
+#[derive(Default)]
+struct State {
+    service: Option<Service>,
+    handlers: Handlers,
+    tasks: executor::Tasks,
+    timers: timer::Timers,
+    stack: stack::Stack,
+}
+
+impl State {
+    // Exchange state of the current worker with this one
+    fn swap(&mut self) {
+        SERVICE.with(|service| std::mem::swap(&mut *service.borrow_mut(), &mut self.service));
+        HANDLERS.with(|handlers| std::mem::swap(&mut *handlers.borrow_mut(), &mut self.handlers));
+        executor::swap(&mut self.tasks);
+        timer::swap(&mut self.timers);
+        stack::swap(&mut self.stack);
+    }
+}
+
+// Message handlers by channel name, message handler is under DEFAULT_CHANNEL
+#[derive(Default)]
+struct Handlers {
+    slots: HashMap<String, Box<dyn HandlerMut>>,
+    // Incremented when handlers are shut down, so handler which was running
+    // at the time is not put back into its slot
+    generation: u64,
+    // Number of handlers running, nested on_message calls included
+    running: usize,
+    // Channels which handlers are running, their messages are queued meanwhile
+    busy: HashSet<String>,
+    // Exit code of close requested by running handler
+    closing: Option<i32>,
+}
+
+// Handler taken out of its slot for the time of the call, it is put back
+// even if the call panics, so worker keeps running after recoverable panics
+struct Taken {
+    channel: String,
+    generation: u64,
+    handler: Option<Box<dyn HandlerMut>>,
+}
+
+impl Taken {
+    fn take(channel: &str) -> Option<Self> {
+        HANDLERS.with(|handlers| {
+            let mut handlers = handlers.borrow_mut();
+            let handler = handlers.slots.remove(channel)?;
+            handlers.running += 1;
+            handlers.busy.insert(channel.to_string());
+            Some(Taken {
+                channel: channel.to_string(),
+                generation: handlers.generation,
+                handler: Some(handler),
+            })
+        })
+    }
+
+    // Puts handler back, returns it if worker was killed meanwhile
+    fn restore(&mut self) -> Option<Box<dyn HandlerMut>> {
+        let handler = self.handler.take()?;
+        HANDLERS
+            .try_with(|handlers| {
+                let mut handlers = handlers.borrow_mut();
+                handlers.running -= 1;
+                handlers.busy.remove(&self.channel);
+                if handlers.generation != self.generation {
+                    return Some(handler);
+                }
+                // Keep replacement if handler was changed during the call
+                handlers
+                    .slots
+                    .entry(self.channel.clone())
+                    .or_insert(handler);
+                None
+            })
+            .ok()
+            .flatten()
+    }
+}
+
+impl Drop for Taken {
+    // Handler panicked, killed worker does not get on_shutdown from unwinding
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+// Restores previous worker when entered instance is left, even on panic
+struct Entered<'a> {
+    sw: &'a ServiceWorker,
+    previous: usize,
+}
+
+impl Drop for Entered<'_> {
+    fn drop(&mut self) {
+        self.sw.state.borrow_mut().swap();
+        self.sw.entered.set(false);
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+// Input, output and queue of the worker
+struct Service {
+    output: Output,
+    input: Box<dyn Read>,
+    // Files opened for input and output, removed on drop if cleanup was requested
+    cleanup: Vec<String>,
+    // Messages received while there was no handler for their channel
+    pending: VecDeque<(String, Vec<u8>)>,
+    queue_limit: usize,
+    overflow: OverflowPolicy,
+    report_errors: bool,
+    // Init payload received before message handler was set
+    init: Option<Vec<u8>>,
+    // Ready control message was posted
+    ready: bool,
+    // Session recording, see ServiceOptions::with_recording
+    recorder: Option<Recorder>,
+}
+
+// Destination of posted messages
+enum Output {
+    Stream(Box<dyn Write>),
+    // Host reads messages straight from linear memory, see FileOptions::Host
+    #[cfg(all(target_os = "wasi", feature = "host-output"))]
+    Host,
 }
 
 /// Handler for incoming messages via ServiceWorker
+///
+/// Lifecycle hooks are optional, errors returned from them are reported
+/// same way as errors of queued messages.
 pub trait Handler {
-    fn on_message(&self, msg: &[u8]) -> std::io::Result<()>;
+    fn on_message(&self, msg: &[u8]) -> Result<()>;
+
+    /// Called when handler is set, before it receives any message
+    fn on_start(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called with init payload sent by the host, message handler only,
+    /// see [control](crate::control)
+    fn on_init(&self, _payload: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when worker is about to terminate
+    fn on_shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Handler for incoming messages which requires mutable access to its state,
+/// see ServiceWorker::set_message_handler_mut and Handler for lifecycle hooks.
+pub trait HandlerMut {
+    fn on_message(&mut self, msg: &[u8]) -> Result<()>;
+
+    fn on_start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_init(&mut self, _payload: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Handler for incoming messages which processes them asynchronously,
+/// see ServiceWorker::set_async_handler.
+///
+/// Returned future is spawned on worker executor, it may await other events
+/// (replies, timers) and will be polled when woken.
+/// Lifecycle hooks are synchronous, see Handler.
+pub trait AsyncHandler {
+    fn on_message(&self, msg: Vec<u8>) -> LocalFuture<Result<()>>;
+
+    fn on_start(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_init(&self, _payload: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Handler installed via ServiceWorker::set_message_handler
+struct Shared(Box<dyn Handler>);
+
+impl HandlerMut for Shared {
+    fn on_message(&mut self, msg: &[u8]) -> Result<()> {
+        self.0.on_message(msg)
+    }
+
+    fn on_start(&mut self) -> Result<()> {
+        self.0.on_start()
+    }
+
+    fn on_init(&mut self, payload: &[u8]) -> Result<()> {
+        self.0.on_init(payload)
+    }
+
+    fn on_shutdown(&mut self) -> Result<()> {
+        self.0.on_shutdown()
+    }
+}
+
+// Handler installed via ServiceWorker::set_async_handler
+struct Spawner(Box<dyn AsyncHandler>);
+
+impl HandlerMut for Spawner {
+    fn on_message(&mut self, msg: &[u8]) -> Result<()> {
+        let future = self.0.on_message(msg.to_vec());
+        executor::spawn_local(async move {
+            if let Err(err) = future.await {
+                if let Err(err) = ServiceWorker::report_error(err) {
+                    eprintln!("Worker failed to process message: {:?}", err);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn on_start(&mut self) -> Result<()> {
+        self.0.on_start()
+    }
+
+    fn on_init(&mut self, payload: &[u8]) -> Result<()> {
+        self.0.on_init(payload)
+    }
+
+    fn on_shutdown(&mut self) -> Result<()> {
+        self.0.on_shutdown()
+    }
 }
 
 thread_local! {
-  static SERVICE: RefCell<Option<ServiceWorker>> = RefCell::new(None);
-  static HANDLER: RefCell<Option<Box<dyn Handler>>> = RefCell::new(None);
+  static SERVICE: RefCell<Option<Service>> = const { RefCell::new(None) };
+  static HANDLERS: RefCell<Handlers> = RefCell::new(Handlers::default());
+  // Address of the entered instance, 0 for default worker
+  static CURRENT: Cell<usize> = const { Cell::new(0) };
 }
 
 impl ServiceWorker {
-    /// Initialize ServiceWorker instance.
-    /// ServiceWorker operates as singleton, all struct methods are static.
-    /// Unless initialized all methods will result in error io::ErrorKind::NotConnected.
-    pub fn initialize(options: ServiceOptions) -> io::Result<()> {
-        let output = match &options.output {
-            FileOptions::File(path) => File::create(path)?,
+    /// Create independent worker instance, it is killed when dropped
+    pub fn new(options: ServiceOptions) -> Result<Self> {
+        let sw = Self {
+            state: RefCell::new(State::default()),
+            entered: Cell::new(false),
+        };
+        sw.enter(|| Self::initialize(options))?;
+        Ok(sw)
+    }
+
+    /// Run closure with this instance as current worker of the thread,
+    /// so static methods operate on it. Instances may be entered from
+    /// handlers of other instances, previous worker is restored on return.
+    ///
+    /// Panics if instance is entered from another instance it has entered itself.
+    pub fn enter<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        match self.try_enter(f) {
+            Ok(result) => result,
+            Err(_) => panic!("ServiceWorker instance is entered by outer call"),
+        }
+    }
+
+    // Same as enter with Error::Reentrant instead of panic
+    fn try_enter<T, F: FnOnce() -> T>(&self, f: F) -> Result<T> {
+        let id = self as *const Self as usize;
+        if CURRENT.with(|current| current.get()) == id {
+            return Ok(f());
+        }
+        if self.entered.replace(true) {
+            return Err(Error::Reentrant);
+        }
+        self.state.borrow_mut().swap();
+        let previous = CURRENT.with(|current| current.replace(id));
+        let _entered = Entered { sw: self, previous };
+        Ok(f())
+    }
+
+    /// Process message as if it was sent by the host to the message handler,
+    /// it is queued if handler is not set
+    pub fn dispatch(&self, msg: &[u8]) -> Result<()> {
+        self.dispatch_channel(DEFAULT_CHANNEL, msg)
+    }
+
+    /// Process message as if it was sent by the host to the named channel.
+    ///
+    /// Fails with Error::Reentrant if instance is entered from another instance
+    /// it has entered itself.
+    pub fn dispatch_channel(&self, channel: &str, msg: &[u8]) -> Result<()> {
+        self.try_enter(|| {
+            let result = Self::deliver(channel.to_string(), msg.to_vec());
+            executor::poll_pending();
+            result
+        })?
+    }
+
+    /// Post message to the output of this instance, see ServiceWorker::post_message
+    pub fn post(&self, msg: &[u8]) -> Result<()> {
+        self.try_enter(|| Self::post_message(msg))?
+    }
+
+    /// Set message handler of this instance, see ServiceWorker::set_message_handler
+    pub fn set_handler(&self, new_handler: Box<dyn Handler>) {
+        self.enter(|| Self::set_message_handler(new_handler))
+    }
+
+    /// Same as set_handler for handlers which need `&mut self`
+    pub fn set_handler_mut(&self, new_handler: Box<dyn HandlerMut>) {
+        self.enter(|| Self::set_message_handler_mut(new_handler))
+    }
+
+    /// Initialize default ServiceWorker of the current thread, or entered instance.
+    /// Unless initialized all methods will result in Error::NotInitialized.
+    pub fn initialize(options: ServiceOptions) -> Result<()> {
+        let ServiceOptions {
+            input,
+            output,
+            cleanup,
+            queue_limit,
+            overflow,
+            report_errors,
+            record,
+        } = options;
+        let mut files = Vec::new();
+        let output = open_output(output, &mut files)?;
+        let input = open_input(input, &mut files)?;
+        // Session file is not removed on cleanup
+        let recorder = match record.map(|record| open_output(record, &mut Vec::new())) {
+            Some(Ok(Output::Stream(writer))) => Some(Recorder::new(writer)),
+            #[cfg(all(target_os = "wasi", feature = "host-output"))]
+            Some(Ok(Output::Host)) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Session cannot be recorded to FileOptions::Host",
+                )))
+            }
+            Some(Err(err)) => return Err(err.into()),
+            None => None,
         };
-        let sw = ServiceWorker {
+        let sw = Service {
             output,
-            input: io::stdin(),
-            options,
+            input,
+            cleanup: if cleanup { files } else { Vec::new() },
+            pending: VecDeque::new(),
+            queue_limit,
+            overflow,
+            report_errors,
+            init: None,
+            ready: false,
+            recorder,
         };
-        SERVICE.with(|service| service.replace(Some(sw)));
-        Ok(())
+        if report_errors {
+            report::install_panic_hook();
+        }
+        SERVICE.with(|service| -> Result<()> {
+            let mut service = service.try_borrow_mut().map_err(|_| Error::Reentrant)?;
+            *service = Some(sw);
+            Ok(())
+        })?;
+        #[cfg(feature = "log")]
+        super::logger::attach();
+        // Message handler could be set before initialization
+        Self::signal_ready()
     }
 
     /// Message handler is required to process incoming messages.
-    /// Please note, there is no queue therefore messages received before handler initialized will be lost.
+    /// Messages received before handler initialized are queued and delivered in order
+    /// right after handler is set, see ServiceOptions::with_queue for limits.
+    /// Errors returned by handler for queued messages are printed to stderr.
     pub fn set_message_handler(new_handler: Box<dyn Handler>) {
-        HANDLER.with(|handler| handler.replace(Some(new_handler)));
+        Self::set_message_handler_mut(Box::new(Shared(new_handler)));
+    }
+
+    /// Same as set_message_handler for handlers which need `&mut self`.
+    ///
+    /// Handler may replace itself while processing message, then new handler
+    /// will receive consequent messages.
+    pub fn set_message_handler_mut(new_handler: Box<dyn HandlerMut>) {
+        Self::install_handler(DEFAULT_CHANNEL, new_handler);
+    }
+
+    /// Set handler for messages of the named channel, see ServiceWorker::channel.
+    ///
+    /// Messages of the channel received before message handler is set are queued,
+    /// after that messages of channels without handler result in Error::NoHandler.
+    /// Names starting with `$` are reserved, they result in Error::ReservedChannel,
+    /// names longer than 255 bytes result in Error::ChannelNameTooLong.
+    pub fn set_channel_handler(channel: &str, new_handler: Box<dyn Handler>) -> Result<()> {
+        Self::set_channel_handler_mut(channel, Box::new(Shared(new_handler)))
+    }
+
+    /// Same as set_channel_handler for handlers which need `&mut self`.
+    ///
+    /// Handler::on_start is called before handler receives queued messages.
+    pub fn set_channel_handler_mut(channel: &str, new_handler: Box<dyn HandlerMut>) -> Result<()> {
+        channel::check_name(channel)?;
+        Self::install_handler(channel, new_handler);
+        Ok(())
+    }
+
+    // Handler of reserved channel, e.g. log level control
+    #[cfg(feature = "log")]
+    pub(crate) fn set_reserved_handler(channel: &str, new_handler: Box<dyn Handler>) {
+        Self::install_handler(channel, Box::new(Shared(new_handler)));
+    }
+
+    // Message handler also receives init payload if it arrived earlier and
+    // ready control message is posted once it is set, see [control](crate::control).
+    fn install_handler(channel: &str, mut new_handler: Box<dyn HandlerMut>) {
+        if let Err(err) = new_handler.on_start() {
+            Self::hook_failed(err);
+        }
+        let init = if channel == DEFAULT_CHANNEL {
+            with_service(|sw| Ok(sw.init.take())).unwrap_or(None)
+        } else {
+            None
+        };
+        if let Some(payload) = init {
+            if let Err(err) = new_handler.on_init(&payload) {
+                Self::hook_failed(err);
+            }
+        }
+        HANDLERS.with(|handlers| {
+            handlers
+                .borrow_mut()
+                .slots
+                .insert(channel.to_string(), new_handler)
+        });
+        if let Err(err) = Self::signal_ready() {
+            eprintln!("Worker failed to post ready message: {:?}", err);
+        }
+        Self::deliver_pending();
+    }
+
+    // Posts ready control message once service is initialized and message handler is set
+    fn signal_ready() -> Result<()> {
+        if !has_handler(DEFAULT_CHANNEL) {
+            return Ok(());
+        }
+        let ready = with_service(|sw| Ok(std::mem::replace(&mut sw.ready, true)));
+        match ready {
+            Ok(false) => Self::post_channel_message(CONTROL_CHANNEL, &[control::READY]),
+            // Not initialized yet, initialize will post it
+            Ok(true) | Err(Error::NotInitialized) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn hook_failed(err: Error) {
+        if let Err(err) = Self::report_error(err) {
+            eprintln!("Worker lifecycle hook failed: {:?}", err);
+        }
+    }
+
+    /// Sender for the named channel, allowing independent subsystems of the worker
+    /// to share single input and output.
+    /// Names starting with `$` are reserved for wasi-worker, they result in
+    /// Error::ReservedChannel, names longer than 255 bytes result in
+    /// Error::ChannelNameTooLong.
+    ///
+    /// Example usage:
+    /// ```
+    /// use wasi_worker::ServiceWorker;
+    /// let progress = ServiceWorker::channel("progress").expect("ServiceWorker::channel");
+    /// progress.post_message(b"42%");
+    /// ```
+    pub fn channel(name: &str) -> Result<Channel> {
+        channel::check_name(name)?;
+        Ok(Channel::new(name))
+    }
+
+    /// Same as set_message_handler for handlers which process messages asynchronously.
+    ///
+    /// Errors returned from handler futures are printed to stderr.
+    ///
+    /// Example usage:
+    /// ```
+    /// use wasi_worker::{AsyncHandler, LocalFuture, ServiceWorker};
+    ///
+    /// struct MyWorker;
+    /// impl AsyncHandler for MyWorker {
+    ///   fn on_message(&self, msg: Vec<u8>) -> LocalFuture<wasi_worker::Result<()>> {
+    ///     Box::pin(async move {
+    ///       // await for replies, timers, etc.
+    ///       ServiceWorker::post_message(&msg)
+    ///     })
+    ///   }
+    /// }
+    /// ServiceWorker::set_async_handler(Box::new(MyWorker));
+    /// ```
+    pub fn set_async_handler(new_handler: Box<dyn AsyncHandler>) {
+        Self::set_message_handler_mut(Box::new(Spawner(new_handler)));
+    }
+
+    /// Push handler on top of the handler stack, see [stack](crate::stack).
+    ///
+    /// Handler::on_start is called before handler receives messages, stack becomes
+    /// message handler of the worker on the first push replacing the current one.
+    /// Handler may push and pop handlers while processing message.
+    pub fn push_handler(new_handler: Box<dyn StackHandler>) {
+        if let Some(dispatcher) = stack::push(new_handler) {
+            Self::set_message_handler_mut(Box::new(dispatcher));
+        }
+    }
+
+    /// Remove top handler of the handler stack calling its on_shutdown hook,
+    /// returns false if stack is empty.
+    ///
+    /// Handler which pops itself is shut down once it returns.
+    pub fn pop_handler() -> bool {
+        stack::pop()
+    }
+
+    /// Set handler for messages passed down by every handler of the stack
+    pub fn set_fallback_handler(new_handler: Box<dyn Handler>) {
+        stack::set_fallback(new_handler)
+    }
+
+    /// Spawn future on worker executor, it is polled first time on next
+    /// ServiceWorker::on_message or poll_tasks call and then whenever it is woken.
+    pub fn spawn_local<F: std::future::Future<Output = ()> + 'static>(future: F) {
+        executor::spawn_local(future)
+    }
+
+    /// Call callback once after given delay, host glue schedules the call.
+    ///
+    /// In native targets time is virtual, see [timer::advance](crate::timer::advance).
+    ///
+    /// Example usage:
+    /// ```
+    /// use std::time::Duration;
+    /// use wasi_worker::ServiceWorker;
+    /// let handle = ServiceWorker::set_timeout(Duration::from_secs(1), || {
+    ///   ServiceWorker::post_message(b"timeout").expect("ServiceWorker::post_message");
+    /// });
+    /// handle.cancel();
+    /// ```
+    pub fn set_timeout<F: FnOnce() + 'static>(delay: Duration, callback: F) -> TimerHandle {
+        timer::set_timeout(delay, callback)
+    }
+
+    /// Call callback repeatedly with given period until returned handle is cancelled
+    pub fn set_interval<F: FnMut() + 'static>(period: Duration, callback: F) -> TimerHandle {
+        timer::set_interval(period, callback)
     }
 
     /// This method is a trigger
     /// This is workaround while we don't have wasi::poll_oneoff,
     /// ideally we shall just poll and wait for FD_READ event.
-    pub fn on_message() -> io::Result<usize> {
-        let mut buf: [u8; 1000] = [0; 1000];
-        let len = SERVICE.with(|service| {
-            if let Some(sw) = &mut *service.borrow_mut() {
-                sw.input.read(&mut buf)
-            } else {
-                Err(io::Error::new(
-                    io::ErrorKind::ConnectionRefused,
-                    "Cannot borrow service mutably",
-                ))
+    ///
+    /// Reads exactly one length-prefixed message from input and passes it to handler
+    /// of its channel, returns length of the message. When input has no pending messages
+    /// returns Ok(0) without calling handler. If handler is not set message is queued.
+    ///
+    /// Futures spawned on worker executor are polled after message is processed.
+    pub fn on_message() -> Result<usize> {
+        let result = Self::read_message();
+        executor::poll_pending();
+        result
+    }
+
+    fn read_message() -> Result<usize> {
+        let frame = with_service(|sw| Ok(read_frame(&mut sw.input)?))?;
+        match frame {
+            Some(frame) => Self::receive(frame),
+            None => Ok(0),
+        }
+    }
+
+    /// Process message frame (without length header) passed by the host
+    /// directly in linear memory, see `message_ready_ptr` export.
+    pub(crate) fn on_message_frame(frame: Vec<u8>) -> Result<usize> {
+        let result = Self::receive(frame);
+        executor::poll_pending();
+        result
+    }
+
+    // Passes payload of the frame to handler of its channel or queues it
+    fn receive(frame: Vec<u8>) -> Result<usize> {
+        let (channel, msg) = split_channel(frame).map_err(|err| Error::Decode(err.to_string()))?;
+        let len = msg.len();
+        Self::deliver(channel, msg)?;
+        Ok(len)
+    }
+
+    fn deliver(channel: String, msg: Vec<u8>) -> Result<()> {
+        // Nothing to record into while service is not initialized
+        let _ = with_service(|sw| {
+            sw.record(Direction::Inbound, &channel, &msg);
+            Ok(())
+        });
+        if channel == CONTROL_CHANNEL {
+            Self::on_control(msg)
+        } else if has_handler(&channel) {
+            let result = Self::call_handler(&channel, &msg);
+            // Messages could be queued while handler was busy
+            Self::deliver_pending();
+            result
+        } else if channel != DEFAULT_CHANNEL && !is_set(&channel) && is_set(DEFAULT_CHANNEL) {
+            // Worker is set up, channel won't get its handler
+            Err(Error::NoHandler(channel))
+        } else {
+            Self::push_pending(channel, msg)
+        }
+    }
+
+    // Handles lifecycle control message from the host
+    fn on_control(msg: Vec<u8>) -> Result<()> {
+        match msg.first() {
+            Some(&control::INIT) => {
+                let payload = msg[1..].to_vec();
+                if has_handler(DEFAULT_CHANNEL) {
+                    Self::with_handler(DEFAULT_CHANNEL, |handler| handler.on_init(&payload))
+                } else {
+                    with_service(|sw| {
+                        sw.init = Some(payload);
+                        Ok(())
+                    })
+                }
             }
-        })?;
-        HANDLER.with(|handler| {
-            if let Some(handler) = &*handler.borrow() {
-                handler.on_message(&buf[0..len])?;
-                Ok(len)
-            } else {
-                Err(io::Error::new(
-                    io::ErrorKind::NotConnected,
-                    "Worker was not initialized",
-                ))
+            _ => Err(Error::Decode("Unknown control message".to_string())),
+        }
+    }
+
+    // Delivers queued messages which have handler to receive them
+    fn deliver_pending() {
+        while let Some((channel, msg)) = Self::pop_pending() {
+            if let Err(err) = Self::call_handler(&channel, &msg).or_else(Self::report_error) {
+                eprintln!("Worker failed to process queued message: {:?}", err);
+            }
+        }
+    }
+
+    fn push_pending(channel: String, msg: Vec<u8>) -> Result<()> {
+        let msg = (channel, msg);
+        with_service(|sw| {
+            if sw.pending.len() < sw.queue_limit {
+                sw.pending.push_back(msg);
+                return Ok(());
+            }
+            match sw.overflow {
+                OverflowPolicy::DropOldest => {
+                    sw.pending.pop_front();
+                    if sw.queue_limit > 0 {
+                        sw.pending.push_back(msg);
+                    }
+                    Ok(())
+                }
+                OverflowPolicy::DropNewest => Ok(()),
+                OverflowPolicy::Error => Err(Error::QueueFull),
             }
         })
     }
 
+    // Takes the first queued message which channel has handler
+    fn pop_pending() -> Option<(String, Vec<u8>)> {
+        SERVICE.with(|service| {
+            let mut service = service.try_borrow_mut().ok()?;
+            let pending = &mut service.as_mut()?.pending;
+            let index = pending
+                .iter()
+                .position(|(channel, _)| has_handler(channel))?;
+            pending.remove(index)
+        })
+    }
+
+    fn call_handler(channel: &str, msg: &[u8]) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("on_message", channel, len = msg.len()).entered();
+        Self::with_handler(channel, |handler| handler.on_message(msg))
+    }
+
+    // Handler is taken out of its slot for the time of the call, so it can
+    // post messages, install another handler, kill or close the worker without
+    // borrowing conflicts. Messages received by its channel meanwhile are
+    // queued and delivered after it returns.
+    fn with_handler<F>(channel: &str, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn HandlerMut) -> Result<()>,
+    {
+        let mut taken =
+            Taken::take(channel).ok_or_else(|| Error::NoHandler(channel.to_string()))?;
+        let result = f(taken.handler.as_mut().expect("taken handler").as_mut());
+        let shutdown = taken.restore();
+        // Close waits for the outermost handler
+        let closing = HANDLERS.with(|handlers| {
+            let mut handlers = handlers.borrow_mut();
+            match handlers.running {
+                0 => handlers.closing.take(),
+                _ => None,
+            }
+        });
+        // Worker was killed by the handler, it is shut down once it returns
+        if let Some(mut handler) = shutdown {
+            if let Err(err) = handler.on_shutdown() {
+                Self::hook_failed(err);
+            }
+        }
+        // Handler is back in its slot and receives on_shutdown with the others
+        if let Some(code) = closing {
+            Self::terminate(code);
+        }
+        result
+    }
+
+    /// Post error on ERROR_CHANNEL if error reporting is enabled,
+    /// otherwise error is returned back to the caller.
+    pub(crate) fn report_error(err: Error) -> Result<()> {
+        if !Self::is_reporting_errors() {
+            return Err(err);
+        }
+        let report = encode_report(&ErrorReport::from(&err));
+        Self::post_channel_message(ERROR_CHANNEL, &report)
+    }
+
+    pub(crate) fn is_reporting_errors() -> bool {
+        with_service(|sw| Ok(sw.report_errors)).unwrap_or(false)
+    }
+
     /// Post message to external consumers
     ///
+    /// Every call results in exactly one message delivered to the consumer,
+    /// see [framing](crate::framing) for wire format.
+    ///
     /// Example usage:
     /// ```
     /// use wasi_worker::ServiceWorker;
     /// ServiceWorker::post_message(b"mymesage");
     /// ```
-    pub fn post_message(msg: &[u8]) -> std::io::Result<()> {
-        SERVICE.with(|service| {
-            if let Some(sw) = &mut *service.borrow_mut() {
-                sw.output.write_all(msg)
-            } else {
-                Err(io::Error::new(
-                    io::ErrorKind::NotConnected,
-                    "Service was not initialized",
-                ))
+    pub fn post_message(msg: &[u8]) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("post_message", channel = DEFAULT_CHANNEL, len = msg.len())
+            .entered();
+        Self::post_channel_message(DEFAULT_CHANNEL, msg)
+    }
+
+    pub(crate) fn post_channel_message(channel: &str, msg: &[u8]) -> Result<()> {
+        if 1 + channel.len() + msg.len() > u32::MAX as usize {
+            return Err(Error::MessageTooLarge(msg.len()));
+        }
+        with_service(|sw| {
+            match &mut sw.output {
+                Output::Stream(writer) => write_message(writer, channel, msg)?,
+                #[cfg(all(target_os = "wasi", feature = "host-output"))]
+                Output::Host => host::post_message(channel, msg)?,
             }
+            sw.record(Direction::Outbound, channel, msg);
+            Ok(())
         })
     }
 
-    pub fn kill() -> () {
+    /// Encode message with given codec and post it to external consumers
+    ///
+    /// Example usage:
+    /// ```
+    /// # #[cfg(feature = "json")] {
+    /// use wasi_worker::{codec::Json, ServiceWorker};
+    /// ServiceWorker::post_typed(&Json, &vec![1, 2, 3]);
+    /// # }
+    /// ```
+    pub fn post_typed<T, C: Codec<T>>(codec: &C, msg: &T) -> Result<()> {
+        let data = codec.encode(msg)?;
+        Self::post_message(&data)
+    }
+
+    /// Drop service and handlers, Handler::on_shutdown is called before service is dropped.
+    ///
+    /// Safe to call from a handler: the calling handler is not put back and gets
+    /// its on_shutdown once it returns, after the service is gone.
+    pub fn kill() {
+        Self::shutdown_handlers();
+        timer::clear();
+        executor::clear();
         SERVICE.with(|service| service.replace(None));
-        HANDLER.with(|handler| handler.replace(None));
+    }
+
+    // Removes all handlers calling their on_shutdown hooks, including ones
+    // installed by the hooks. Running handlers are shut down when they return.
+    fn shutdown_handlers() {
+        loop {
+            let handlers = HANDLERS.with(|handlers| {
+                let mut handlers = handlers.borrow_mut();
+                handlers.generation += 1;
+                std::mem::take(&mut handlers.slots)
+            });
+            if handlers.is_empty() {
+                break;
+            }
+            for (_, mut handler) in handlers {
+                if let Err(err) = handler.on_shutdown() {
+                    Self::hook_failed(err);
+                }
+            }
+        }
+    }
+
+    /// Gracefully terminate worker process with given exit code.
+    ///
+    /// Handler::on_shutdown hooks are called, then host is notified with closing
+    /// control message (see [control](crate::control)), output is flushed,
+    /// files are removed if cleanup was requested and process exits.
+    ///
+    /// Called from a handler, close returns and worker is closed once the handler
+    /// returns, the handler receives on_shutdown same as others. Otherwise close
+    /// does not return.
+    pub fn close(code: i32) {
+        let deferred = HANDLERS.with(|handlers| {
+            let mut handlers = handlers.borrow_mut();
+            if handlers.running == 0 {
+                return false;
+            }
+            handlers.closing.get_or_insert(code);
+            true
+        });
+        if !deferred {
+            Self::terminate(code);
+        }
+    }
+
+    fn terminate(code: i32) -> ! {
+        if let Err(err) = Self::shutdown(code) {
+            eprintln!("Worker failed to close gracefully: {:?}", err);
+        }
+        std::process::exit(code)
+    }
+
+    // Everything close does except process exit
+    pub(crate) fn shutdown(code: i32) -> Result<()> {
+        Self::shutdown_handlers();
+        let mut closing = vec![control::CLOSING];
+        closing.extend_from_slice(&code.to_le_bytes());
+        let result = Self::post_channel_message(CONTROL_CHANNEL, &closing).and_then(|_| {
+            with_service(|sw| match &mut sw.output {
+                Output::Stream(writer) => Ok(writer.flush()?),
+                #[cfg(all(target_os = "wasi", feature = "host-output"))]
+                Output::Host => Ok(()),
+            })
+        });
+        // Dropping service removes files if cleanup was requested
+        Self::kill();
+        result
     }
 }
 
+// Runs closure with initialized service, it is not available while in use by the caller
+fn with_service<T, F: FnOnce(&mut Service) -> Result<T>>(f: F) -> Result<T> {
+    SERVICE.with(|service| {
+        let mut service = service.try_borrow_mut().map_err(|_| Error::Reentrant)?;
+        match &mut *service {
+            Some(sw) => f(sw),
+            None => Err(Error::NotInitialized),
+        }
+    })
+}
+
+fn has_handler(channel: &str) -> bool {
+    HANDLERS.with(|handlers| handlers.borrow().slots.contains_key(channel))
+}
+
+// Channel has handler, which could be running at the moment
+fn is_set(channel: &str) -> bool {
+    HANDLERS.with(|handlers| {
+        let handlers = handlers.borrow();
+        handlers.slots.contains_key(channel) || handlers.busy.contains(channel)
+    })
+}
+
 impl Drop for ServiceWorker {
     fn drop(&mut self) {
-        if self.options.cleanup {
-            let clr = match &self.options.output {
-                FileOptions::File(output) => std::fs::remove_file(output),
-            };
-            match clr {
-                Ok(_) => (),
-                Err(err) => eprintln!("Failed to remove file {}", err),
+        self.enter(Self::kill)
+    }
+}
+
+impl Service {
+    // Recording failure does not affect messages, recording is stopped instead
+    fn record(&mut self, direction: Direction, channel: &str, msg: &[u8]) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(direction, channel, msg) {
+                eprintln!("Worker stopped recording session: {:?}", err);
+                self.recorder = None;
+            }
+        }
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        for file in self.cleanup.iter() {
+            if let Err(err) = std::fs::remove_file(file) {
+                eprintln!("Failed to remove file {}", err);
             }
         }
     }
 }
+
+fn open_input(input: FileOptions, files: &mut Vec<String>) -> io::Result<Box<dyn Read>> {
+    match input {
+        FileOptions::File(path) => {
+            let file = File::open(&path)?;
+            files.push(path);
+            Ok(Box::new(file))
+        }
+        FileOptions::Stdin => Ok(Box::new(io::stdin())),
+        #[cfg(any(unix, target_os = "wasi"))]
+        FileOptions::Fd(fd) => Ok(Box::new(File::from(fd))),
+        FileOptions::Memory(buffer) => Ok(Box::new(buffer)),
+        FileOptions::Reader(reader) => Ok(reader),
+        #[cfg(all(target_os = "wasi", feature = "host-output"))]
+        FileOptions::Host => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Input cannot be configured with output only FileOptions",
+        )),
+        FileOptions::Stdout => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Input cannot be configured with output only FileOptions",
+        )),
+    }
+}
+
+fn open_output(output: FileOptions, files: &mut Vec<String>) -> io::Result<Output> {
+    let writer: Box<dyn Write> = match output {
+        FileOptions::File(path) => {
+            let file = File::create(&path)?;
+            files.push(path);
+            Box::new(file)
+        }
+        FileOptions::Stdout => Box::new(io::stdout()),
+        #[cfg(any(unix, target_os = "wasi"))]
+        FileOptions::Fd(fd) => Box::new(File::from(fd)),
+        FileOptions::Memory(buffer) => Box::new(buffer),
+        #[cfg(all(target_os = "wasi", feature = "host-output"))]
+        FileOptions::Host => return Ok(Output::Host),
+        FileOptions::Stdin | FileOptions::Reader(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Output cannot be configured with input only FileOptions",
+            ))
+        }
+    };
+    Ok(Output::Stream(writer))
+}
+
+#[cfg(all(target_os = "wasi", feature = "host-output"))]
+mod host {
+    use std::io;
+
+    mod imports {
+        #[link(wasm_import_module = "wasi_worker")]
+        extern "C" {
+            pub fn post_message(
+                channel_ptr: *const u8,
+                channel_len: usize,
+                ptr: *const u8,
+                len: usize,
+            );
+        }
+    }
+
+    // Host copies message out of linear memory before the call returns
+    pub fn post_message(channel: &str, msg: &[u8]) -> io::Result<()> {
+        if channel.len() > u8::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Channel name is too long",
+            ));
+        }
+        unsafe { imports::post_message(channel.as_ptr(), channel.len(), msg.as_ptr(), msg.len()) }
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+//! Recording of message sessions and their replay for deterministic debugging,
+//! see ServiceOptions::with_recording.
+//!
+//! Session file is a sequence of length-prefixed entries (see [framing](crate::framing)):
+//! `[direction: u8][sequence: u64 LE][timestamp: u64 LE][channel][payload]`,
+//! where direction is 0 for message received by the worker and 1 for posted message,
+//! sequence is shared by both directions and starts from 0, timestamp is microseconds
+//! since UNIX epoch and channel name is prefixed with u32 LE length.
+//!
+//! Example usage:
+//! ```
+//! use wasi_worker::session::{self, Session};
+//! use wasi_worker::{FileOptions, Handler, MemoryBuffer, ServiceOptions, ServiceWorker};
+//!
+//! struct Echo;
+//! impl Handler for Echo {
+//!   fn on_message(&self, msg: &[u8]) -> wasi_worker::Result<()> {
+//!     ServiceWorker::post_message(msg)
+//!   }
+//! }
+//!
+//! // Customer's worker records its session
+//! let input = MemoryBuffer::new();
+//! let recording = MemoryBuffer::new();
+//! let opt = ServiceOptions::default()
+//!   .with_input(FileOptions::Memory(input.clone()))
+//!   .with_output(FileOptions::Memory(MemoryBuffer::new()))
+//!   .with_recording(FileOptions::Memory(recording.clone()));
+//! ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+//! ServiceWorker::set_message_handler(Box::new(Echo));
+//! input.push_message(b"ping");
+//! ServiceWorker::on_message().expect("ServiceWorker::on_message");
+//! ServiceWorker::kill();
+//!
+//! // Replay it natively against the handler
+//! let session = Session::read(&mut recording.clone()).expect("Session::read");
+//! let diff = session::replay(&session, ServiceOptions::default(), || {
+//!   ServiceWorker::set_message_handler(Box::new(Echo));
+//! });
+//! assert!(diff.expect("session::replay").is_empty());
+//! ```
+use super::framing::{push_field, read_frame, split_field, timestamp, write_frame};
+use super::{Error, Result};
+use std::io::{self, Read, Write};
+
+const INBOUND: u8 = 0;
+const OUTBOUND: u8 = 1;
+
+/// Channels which are not compared on replay: lifecycle control messages
+/// and log and trace records carrying timestamps
+pub const IGNORED_CHANNELS: [&str; 3] = ["$control", "$log", "$trace"];
+
+/// Whether message was received or posted by the worker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Recorded message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub direction: Direction,
+    pub sequence: u64,
+    /// Microseconds since UNIX epoch
+    pub timestamp: u64,
+    pub channel: String,
+    pub payload: Vec<u8>,
+}
+
+pub fn encode_entry(entry: &Entry) -> Vec<u8> {
+    let direction = match entry.direction {
+        Direction::Inbound => INBOUND,
+        Direction::Outbound => OUTBOUND,
+    };
+    let mut data = vec![direction];
+    data.extend_from_slice(&entry.sequence.to_le_bytes());
+    data.extend_from_slice(&entry.timestamp.to_le_bytes());
+    push_field(&mut data, &entry.channel);
+    data.extend_from_slice(&entry.payload);
+    data
+}
+
+pub fn decode_entry(data: &[u8]) -> Result<Entry> {
+    if data.len() < 17 {
+        return Err(malformed());
+    }
+    let direction = match data[0] {
+        INBOUND => Direction::Inbound,
+        OUTBOUND => Direction::Outbound,
+        _ => return Err(malformed()),
+    };
+    let mut sequence = [0u8; 8];
+    sequence.copy_from_slice(&data[1..9]);
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&data[9..17]);
+    let (channel, payload) = split_field(&data[17..]).ok_or_else(malformed)?;
+    Ok(Entry {
+        direction,
+        sequence: u64::from_le_bytes(sequence),
+        timestamp: u64::from_le_bytes(timestamp),
+        channel: channel.to_string(),
+        payload: payload.to_vec(),
+    })
+}
+
+fn malformed() -> Error {
+    Error::Decode("Malformed session entry".to_string())
+}
+
+/// Recorded session in order of sequence numbers
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Session {
+    pub entries: Vec<Entry>,
+}
+
+impl Session {
+    /// Read all entries of the session file
+    pub fn read<R: Read + ?Sized>(reader: &mut R) -> Result<Self> {
+        let mut entries = Vec::new();
+        while let Some(frame) = read_frame(reader)? {
+            entries.push(decode_entry(&frame)?);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Read session from the file
+    pub fn open(path: &str) -> Result<Self> {
+        Self::read(&mut io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    /// Messages received by the worker
+    pub fn inbound(&self) -> impl Iterator<Item = &Entry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.direction == Direction::Inbound)
+    }
+
+    /// Messages posted by the worker
+    pub fn outbound(&self) -> impl Iterator<Item = &Entry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.direction == Direction::Outbound)
+    }
+}
+
+// Writes entries to the session file, owned by ServiceWorker
+pub(crate) struct Recorder {
+    writer: Box<dyn Write>,
+    sequence: u64,
+}
+
+impl Recorder {
+    pub(crate) fn new(writer: Box<dyn Write>) -> Self {
+        Self {
+            writer,
+            sequence: 0,
+        }
+    }
+
+    // Every entry is written with single frame, so interrupted session stays readable
+    pub(crate) fn record(
+        &mut self,
+        direction: Direction,
+        channel: &str,
+        msg: &[u8],
+    ) -> io::Result<()> {
+        let entry = Entry {
+            direction,
+            sequence: self.sequence,
+            timestamp: timestamp(),
+            channel: channel.to_string(),
+            payload: msg.to_vec(),
+        };
+        self.sequence += 1;
+        write_frame(&mut self.writer, &encode_entry(&entry))
+    }
+}
+
+/// Message which differs between recorded session and its replay,
+/// `None` when there is no message at this position
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference {
+    /// Position among compared posted messages
+    pub index: usize,
+    pub expected: Option<(String, Vec<u8>)>,
+    pub actual: Option<(String, Vec<u8>)>,
+}
+
+/// Feed recorded inbound messages to the handlers installed by setup and
+/// diff messages they post against the recording, empty result means replay
+/// posted exactly the same messages, see IGNORED_CHANNELS.
+///
+/// ServiceWorker of the current thread is initialized with given options and
+/// in-memory input and output (see [MockHost](crate::testing::MockHost)), virtual
+/// time advances by recorded intervals between messages, so timers fire in between
+/// like they did in recording. Error returned by handler stops replay unless
+/// error reporting is enabled in options. Native targets only.
+#[cfg(not(target_os = "wasi"))]
+pub fn replay<F: FnOnce()>(
+    session: &Session,
+    options: super::ServiceOptions,
+    setup: F,
+) -> Result<Vec<Difference>> {
+    use super::testing::MockHost;
+    use std::time::Duration;
+
+    let host = MockHost::with_options(options)?;
+    setup();
+    let mut time = session.entries.first().map(|entry| entry.timestamp);
+    let mut advance = |timestamp: u64| {
+        let elapsed = time.map(|time| timestamp.saturating_sub(time)).unwrap_or(0);
+        host.advance(Duration::from_micros(elapsed));
+        time = Some(timestamp);
+    };
+    for entry in session.inbound() {
+        advance(entry.timestamp);
+        host.push_channel(&entry.channel, &entry.payload);
+        // Same as message_ready export does
+        host.dispatch()
+            .map(drop)
+            .or_else(super::ServiceWorker::report_error)?;
+    }
+    // Timers which fired after the last message
+    if let Some(last) = session.entries.last() {
+        advance(last.timestamp);
+    }
+    let compared = |channel: &String| !IGNORED_CHANNELS.contains(&channel.as_str());
+    let expected: Vec<(String, Vec<u8>)> = session
+        .outbound()
+        .filter(|entry| compared(&entry.channel))
+        .map(|entry| (entry.channel.clone(), entry.payload.clone()))
+        .collect();
+    let actual: Vec<(String, Vec<u8>)> = host
+        .posted_all()
+        .into_iter()
+        .filter(|(channel, _)| compared(channel))
+        .collect();
+    let differences = (0..expected.len().max(actual.len()))
+        .filter_map(|index| {
+            let expected = expected.get(index).cloned();
+            let actual = actual.get(index).cloned();
+            (expected != actual).then_some(Difference {
+                index,
+                expected,
+                actual,
+            })
+        })
+        .collect();
+    Ok(differences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileOptions, Handler, HandlerMut, MemoryBuffer, ServiceOptions, ServiceWorker};
+
+    // Posts running total of message lengths, optionally off by one
+    struct Total(usize, usize);
+    impl HandlerMut for Total {
+        fn on_message(&mut self, msg: &[u8]) -> Result<()> {
+            self.0 += msg.len() + self.1;
+            ServiceWorker::post_message(&[self.0 as u8])
+        }
+    }
+
+    // Receives messages of other channel without replying
+    struct Ignore;
+    impl Handler for Ignore {
+        fn on_message(&self, _msg: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn setup(offset: usize) {
+        ServiceWorker::set_message_handler_mut(Box::new(Total(0, offset)));
+        ServiceWorker::set_channel_handler("other", Box::new(Ignore))
+            .expect("ServiceWorker::set_channel_handler");
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let input = MemoryBuffer::new();
+        let recording = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(MemoryBuffer::new()))
+            .with_recording(FileOptions::Memory(recording.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        input.push_message(b"queued");
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        setup(0);
+        input.push_channel_message("other", b"ignored");
+        input.push_message(b"abc");
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        ServiceWorker::kill();
+
+        let session = Session::read(&mut recording.clone()).expect("Session::read");
+        let summary: Vec<(u64, Direction, &str, &[u8])> = session
+            .entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.sequence,
+                    entry.direction,
+                    entry.channel.as_str(),
+                    entry.payload.as_slice(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, Direction::Inbound, "", &b"queued"[..]),
+                (1, Direction::Outbound, "$control", &[0][..]),
+                (2, Direction::Outbound, "", &[6][..]),
+                (3, Direction::Inbound, "other", &b"ignored"[..]),
+                (4, Direction::Inbound, "", &b"abc"[..]),
+                (5, Direction::Outbound, "", &[9][..]),
+            ]
+        );
+        assert!(session.entries[0].timestamp <= session.entries[5].timestamp);
+
+        let diff = replay(&session, ServiceOptions::default(), || setup(0));
+        assert_eq!(diff.unwrap(), vec![]);
+
+        // Regression in the handler
+        let diff = replay(&session, ServiceOptions::default(), || setup(1));
+        assert_eq!(
+            diff.unwrap(),
+            vec![
+                Difference {
+                    index: 0,
+                    expected: Some(("".to_string(), vec![6])),
+                    actual: Some(("".to_string(), vec![7])),
+                },
+                Difference {
+                    index: 1,
+                    expected: Some(("".to_string(), vec![9])),
+                    actual: Some(("".to_string(), vec![11])),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Stack of message handlers for workers which switch modes, see ServiceWorker::push_handler.
+//!
+//! Handler on top of the stack receives messages first, it returns Flow::Pass to pass
+//! message down to the handler below and may remove itself with ServiceWorker::pop_handler.
+//! Messages passed down by every handler of the stack go to the fallback handler
+//! (ServiceWorker::set_fallback_handler), without it they result in Error::NoHandler.
+//!
+//! Stack is the message handler of the worker since the first push_handler call, until
+//! another message handler is set, which drops stacked handlers.
+//!
+//! Example usage:
+//! ```
+//! use wasi_worker::stack::{Flow, StackHandler};
+//! use wasi_worker::ServiceWorker;
+//!
+//! // Answers queries, anything else goes to the fallback
+//! struct Queries(Vec<u8>);
+//! impl StackHandler for Queries {
+//!   fn on_message(&mut self, msg: &[u8]) -> wasi_worker::Result<Flow> {
+//!     if msg != b"query" {
+//!       return Ok(Flow::Pass);
+//!     }
+//!     ServiceWorker::post_message(&self.0)?;
+//!     Ok(Flow::Handled)
+//!   }
+//! }
+//!
+//! // Collects dataset until it is complete, then switches to queries
+//! struct Loading(Vec<u8>);
+//! impl StackHandler for Loading {
+//!   fn on_message(&mut self, msg: &[u8]) -> wasi_worker::Result<Flow> {
+//!     if msg.is_empty() {
+//!       ServiceWorker::pop_handler();
+//!       ServiceWorker::push_handler(Box::new(Queries(std::mem::take(&mut self.0))));
+//!     } else {
+//!       self.0.extend_from_slice(msg);
+//!     }
+//!     Ok(Flow::Handled)
+//!   }
+//! }
+//!
+//! ServiceWorker::push_handler(Box::new(Loading(Vec::new())));
+//! ```
+use super::framing::DEFAULT_CHANNEL;
+use super::{Error, Handler, HandlerMut, Result, ServiceWorker};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// What happens to the message after handler of the stack has seen it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// Message is processed, handlers below do not receive it
+    Handled,
+    /// Message is passed to the handler below or to the fallback
+    Pass,
+}
+
+/// Handler of the stack, see ServiceWorker::push_handler and Handler for lifecycle hooks.
+pub trait StackHandler {
+    fn on_message(&mut self, msg: &[u8]) -> Result<Flow>;
+
+    /// Called when handler is pushed
+    fn on_start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called with init payload sent by the host, top handler only
+    fn on_init(&mut self, _payload: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when handler is popped or worker is about to terminate
+    fn on_shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct Layer {
+    id: u64,
+    // None while handler is running
+    handler: Option<Box<dyn StackHandler>>,
+}
+
+/// Handler stack of the worker, kept by worker instance which is not entered,
+/// see ServiceWorker::enter.
+#[derive(Default)]
+pub(crate) struct Stack {
+    layers: Vec<Layer>,
+    fallback: Option<Rc<dyn Handler>>,
+    next_id: u64,
+    // Dispatcher is set as message handler
+    installed: bool,
+}
+
+thread_local! {
+  static STACK: RefCell<Stack> = RefCell::new(Stack::default());
+}
+
+/// Exchange handler stack of the current worker with given one
+pub(crate) fn swap(other: &mut Stack) {
+    STACK.with(|stack| std::mem::swap(&mut *stack.borrow_mut(), other));
+}
+
+/// Put handler on top, returns Dispatcher when it has to be set as message handler
+pub(crate) fn push(mut handler: Box<dyn StackHandler>) -> Option<Dispatcher> {
+    if let Err(err) = handler.on_start() {
+        ServiceWorker::hook_failed(err);
+    }
+    STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        let id = stack.next_id;
+        stack.next_id += 1;
+        stack.layers.push(Layer {
+            id,
+            handler: Some(handler),
+        });
+        (!std::mem::replace(&mut stack.installed, true)).then_some(Dispatcher)
+    })
+}
+
+/// Remove top handler, returns false if stack is empty.
+///
+/// Running handler is shut down once it returns.
+pub(crate) fn pop() -> bool {
+    let layer = STACK.with(|stack| stack.borrow_mut().layers.pop());
+    match layer {
+        Some(layer) => {
+            if let Some(handler) = layer.handler {
+                shutdown(handler);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+pub(crate) fn set_fallback(handler: Box<dyn Handler>) {
+    if let Err(err) = handler.on_start() {
+        ServiceWorker::hook_failed(err);
+    }
+    let handler: Rc<dyn Handler> = Rc::from(handler);
+    STACK.with(|stack| stack.borrow_mut().fallback = Some(handler));
+}
+
+fn shutdown(mut handler: Box<dyn StackHandler>) {
+    if let Err(err) = handler.on_shutdown() {
+        ServiceWorker::hook_failed(err);
+    }
+}
+
+fn depth() -> usize {
+    STACK.with(|stack| stack.borrow().layers.len())
+}
+
+// Handler is taken out of its layer for the time of the call, so it can
+// push and pop handlers. Handler which was popped meanwhile is not put back.
+fn with_layer<T, F>(index: usize, f: F) -> Result<T>
+where
+    F: FnOnce(&mut dyn StackHandler) -> Result<T>,
+{
+    let (id, mut handler) = STACK
+        .with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let layer = stack.layers.get_mut(index)?;
+            Some((layer.id, layer.handler.take()?))
+        })
+        .ok_or(Error::Reentrant)?;
+    let result = f(handler.as_mut());
+    let popped = STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        match stack.layers.iter_mut().find(|layer| layer.id == id) {
+            Some(layer) => {
+                layer.handler = Some(handler);
+                None
+            }
+            None => Some(handler),
+        }
+    });
+    if let Some(handler) = popped {
+        shutdown(handler);
+    }
+    result
+}
+
+/// Message handler passing messages down the stack
+pub(crate) struct Dispatcher;
+
+impl HandlerMut for Dispatcher {
+    fn on_message(&mut self, msg: &[u8]) -> Result<()> {
+        let mut level = depth();
+        while level > 0 {
+            level -= 1;
+            if with_layer(level, |handler| handler.on_message(msg))? == Flow::Handled {
+                return Ok(());
+            }
+            // Handlers below could be popped too
+            level = level.min(depth());
+        }
+        let fallback = STACK.with(|stack| stack.borrow().fallback.clone());
+        match fallback {
+            Some(fallback) => fallback.on_message(msg),
+            None => Err(Error::NoHandler(DEFAULT_CHANNEL.to_string())),
+        }
+    }
+
+    fn on_init(&mut self, payload: &[u8]) -> Result<()> {
+        match depth() {
+            0 => Ok(()),
+            depth => with_layer(depth - 1, |handler| handler.on_init(payload)),
+        }
+    }
+
+    // Stack is shut down together with the worker
+    fn on_shutdown(&mut self) -> Result<()> {
+        while pop() {}
+        let fallback = STACK.with(|stack| stack.borrow_mut().fallback.take());
+        match fallback {
+            Some(fallback) => fallback.on_shutdown(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Replaced stack is dropped without shutdown hooks, same as replaced message handler
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        // Ids keep growing, so running handler is not put back into another layer
+        let stack = STACK.try_with(|stack| {
+            let mut stack = stack.try_borrow_mut().ok()?;
+            let next_id = stack.next_id;
+            Some(std::mem::replace(
+                &mut *stack,
+                Stack {
+                    next_id,
+                    ..Stack::default()
+                },
+            ))
+        });
+        drop(stack);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockHost;
+
+    // Handles messages starting with its prefix, pops itself on "<prefix>pop"
+    struct Mode {
+        prefix: u8,
+        events: Rc<RefCell<Vec<String>>>,
+    }
+    impl StackHandler for Mode {
+        fn on_message(&mut self, msg: &[u8]) -> Result<Flow> {
+            if msg.first() != Some(&self.prefix) {
+                return Ok(Flow::Pass);
+            }
+            if &msg[1..] == b"pop" {
+                ServiceWorker::pop_handler();
+            }
+            ServiceWorker::post_message(msg)?;
+            Ok(Flow::Handled)
+        }
+        fn on_start(&mut self) -> Result<()> {
+            let event = format!("start {}", self.prefix as char);
+            self.events.borrow_mut().push(event);
+            Ok(())
+        }
+        fn on_shutdown(&mut self) -> Result<()> {
+            let event = format!("shutdown {}", self.prefix as char);
+            self.events.borrow_mut().push(event);
+            Ok(())
+        }
+    }
+
+    struct Fallback;
+    impl Handler for Fallback {
+        fn on_message(&self, msg: &[u8]) -> Result<()> {
+            ServiceWorker::post_message(&[b"fallback ", msg].concat())
+        }
+    }
+
+    #[test]
+    fn handler_stack() {
+        let host = MockHost::new().expect("MockHost::new");
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mode = |prefix| {
+            Box::new(Mode {
+                prefix,
+                events: events.clone(),
+            })
+        };
+        ServiceWorker::push_handler(mode(b'a'));
+        ServiceWorker::push_handler(mode(b'b'));
+        for msg in [&b"a1"[..], b"b1", b"c1"] {
+            host.push(msg);
+        }
+        let result = host.dispatch();
+        assert!(matches!(result, Err(Error::NoHandler(channel)) if channel.is_empty()));
+        ServiceWorker::set_fallback_handler(Box::new(Fallback));
+        for msg in [&b"c2"[..], b"bpop", b"b2", b"apop", b"a3"] {
+            host.push(msg);
+        }
+        host.dispatch().expect("MockHost::dispatch");
+        assert!(!ServiceWorker::pop_handler());
+        ServiceWorker::push_handler(mode(b'd'));
+        host.send(b"d1").expect("MockHost::send");
+        let posted: Vec<&[u8]> = vec![
+            b"a1",
+            b"b1",
+            b"fallback c2",
+            b"bpop",
+            b"fallback b2",
+            b"apop",
+            b"fallback a3",
+            b"d1",
+        ];
+        assert_eq!(host.posted(), posted);
+        // Stack is shut down with the worker
+        drop(host);
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                "start a",
+                "start b",
+                "shutdown b",
+                "shutdown a",
+                "start d",
+                "shutdown d"
+            ]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! In-memory host for unit testing handlers natively.
+//!
+//! ServiceWorker state is thread local and `cargo test` runs every test in its
+//! own thread, so tests using MockHost do not interfere when run in parallel.
+//!
+//! Example usage:
+//! ```
+//! use wasi_worker::testing::MockHost;
+//! use wasi_worker::{Handler, ServiceWorker};
+//!
+//! struct Echo;
+//! impl Handler for Echo {
+//!   fn on_message(&self, msg: &[u8]) -> wasi_worker::Result<()> {
+//!     ServiceWorker::post_message(msg)
+//!   }
+//! }
+//!
+//! let host = MockHost::new().expect("MockHost::new");
+//! ServiceWorker::set_message_handler(Box::new(Echo));
+//! host.send(b"ping").expect("MockHost::send");
+//! assert_eq!(host.posted(), vec![b"ping".to_vec()]);
+//! ```
+use super::control::{self, CONTROL_CHANNEL};
+use super::framing::{read_message, DEFAULT_CHANNEL};
+use super::{FileOptions, MemoryBuffer, Result, ServiceOptions, ServiceWorker};
+
+/// Initializes ServiceWorker of the current thread with in-memory input and output,
+/// worker is killed when MockHost is dropped.
+pub struct MockHost {
+    input: MemoryBuffer,
+    output: MemoryBuffer,
+}
+
+impl MockHost {
+    /// Mock host with default ServiceOptions
+    pub fn new() -> Result<Self> {
+        Self::with_options(ServiceOptions::default())
+    }
+
+    /// Mock host with given options, input and output are replaced with in-memory buffers
+    pub fn with_options(options: ServiceOptions) -> Result<Self> {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        // Handlers left by previous test running in the same thread
+        ServiceWorker::kill();
+        ServiceWorker::initialize(
+            options
+                .with_input(FileOptions::Memory(input.clone()))
+                .with_output(FileOptions::Memory(output.clone())),
+        )?;
+        Ok(Self { input, output })
+    }
+
+    /// Queue message for the message handler, it is processed by [dispatch](Self::dispatch)
+    pub fn push(&self, msg: &[u8]) {
+        self.input.push_message(msg)
+    }
+
+    /// Queue message for the handler of the named channel
+    pub fn push_channel(&self, channel: &str, msg: &[u8]) {
+        self.input.push_channel_message(channel, msg)
+    }
+
+    /// Queue init payload for Handler::on_init
+    pub fn push_init(&self, payload: &[u8]) {
+        self.input
+            .push_channel_message(CONTROL_CHANNEL, &[&[control::INIT], payload].concat())
+    }
+
+    /// Process all queued messages like host would do on message_ready,
+    /// returns number of processed messages
+    pub fn dispatch(&self) -> Result<usize> {
+        let mut count = 0;
+        while !self.input.is_empty() {
+            ServiceWorker::on_message()?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Push message and dispatch it
+    pub fn send(&self, msg: &[u8]) -> Result<usize> {
+        self.push(msg);
+        self.dispatch()
+    }
+
+    /// Push message to the named channel and dispatch it
+    pub fn send_channel(&self, channel: &str, msg: &[u8]) -> Result<usize> {
+        self.push_channel(channel, msg);
+        self.dispatch()
+    }
+
+    /// Messages posted with ServiceWorker::post_message so far
+    pub fn posted(&self) -> Vec<Vec<u8>> {
+        self.posted_on(DEFAULT_CHANNEL)
+    }
+
+    /// Messages posted to the named channel so far
+    pub fn posted_on(&self, channel: &str) -> Vec<Vec<u8>> {
+        self.output
+            .channel_messages(channel)
+            .expect("ServiceWorker output is always framed")
+    }
+
+    /// Messages posted to all channels so far, in order
+    pub fn posted_all(&self) -> Vec<(String, Vec<u8>)> {
+        let data = self.output.contents();
+        let mut reader = &data[..];
+        let mut messages = Vec::new();
+        while let Some(message) =
+            read_message(&mut reader).expect("ServiceWorker output is always framed")
+        {
+            messages.push(message);
+        }
+        messages
+    }
+
+    /// Forget posted messages, e.g. after test setup
+    pub fn clear_posted(&self) {
+        self.output.clear()
+    }
+
+    /// Move virtual time forward firing due timers, see [timer::advance](crate::timer::advance)
+    #[cfg(not(target_os = "wasi"))]
+    pub fn advance(&self, duration: std::time::Duration) {
+        super::timer::advance(duration)
+    }
+}
+
+impl Drop for MockHost {
+    fn drop(&mut self) {
+        ServiceWorker::kill()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HandlerMut;
+    use std::time::Duration;
+
+    // Counts messages and reports count on "count" channel after a delay
+    #[derive(Default)]
+    struct Counter(usize);
+    impl HandlerMut for Counter {
+        fn on_message(&mut self, _msg: &[u8]) -> Result<()> {
+            self.0 += 1;
+            let count = self.0 as u8;
+            ServiceWorker::set_timeout(Duration::from_millis(10), move || {
+                ServiceWorker::channel("count")
+                    .and_then(|channel| channel.post_message(&[count]))
+                    .expect("post count")
+            });
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mock_host() {
+        let host = MockHost::new().expect("MockHost::new");
+        host.push(b"one");
+        host.push(b"two");
+        // Queued by worker until handler is set
+        assert_eq!(host.dispatch().unwrap(), 2);
+        ServiceWorker::set_message_handler_mut(Box::new(Counter::default()));
+        assert_eq!(host.posted_on(CONTROL_CHANNEL), vec![vec![control::READY]]);
+        host.clear_posted();
+        assert_eq!(host.send(b"three").unwrap(), 1);
+        assert!(host.posted_on("count").is_empty());
+        host.advance(Duration::from_millis(10));
+        assert_eq!(host.posted_on("count"), vec![vec![1], vec![2], vec![3]]);
+        assert!(host.posted().is_empty());
+    }
+
+    #[test]
+    fn parallel_hosts() {
+        let threads: Vec<_> = (0..4u8)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let host = MockHost::new().expect("MockHost::new");
+                    ServiceWorker::post_message(&[i]).expect("post_message");
+                    host.posted()
+                })
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            assert_eq!(thread.join().unwrap(), vec![vec![i as u8]]);
+        }
+    }
+}
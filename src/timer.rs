@@ -0,0 +1,309 @@
+//! Timers scheduled by the host.
+//!
+//! In WASI target with `host-timers` feature timers are armed via `wasi_worker.set_timer`
+//! and `wasi_worker.clear_timer` host imports (see wasi-worker-cli JS glue),
+//! host calls back exported `timer_fired` when timer is due.
+//!
+//! Otherwise, e.g. in native targets or plain WASI hosts without the imports, time is
+//! virtual and starts at zero, it only moves with [advance], which allows to test
+//! timer driven handlers deterministically.
+use super::executor;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+enum Callback {
+    Once(Box<dyn FnOnce()>),
+    Repeat(Box<dyn FnMut()>),
+}
+
+struct Timer {
+    // None while callback is running
+    callback: Option<Callback>,
+    interval: Duration,
+    #[cfg(not(all(target_os = "wasi", feature = "host-timers")))]
+    due: Duration,
+}
+
+thread_local! {
+  static TIMERS: RefCell<HashMap<u32, Timer>> = RefCell::new(HashMap::new());
+  static NEXT_ID: Cell<u32> = const { Cell::new(1) };
+}
+
+/// Handle of scheduled timer, allows to cancel it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerHandle(u32);
+
+impl TimerHandle {
+    /// Cancel timer, callback won't be called anymore.
+    /// It is safe to cancel timer from its own callback or after it has fired.
+    pub fn cancel(self) {
+        let removed = TIMERS.with(|timers| timers.borrow_mut().remove(&self.0));
+        if removed.is_some() {
+            host::clear_timer(self.0);
+        }
+    }
+}
+
+/// Call callback once after given duration
+pub fn set_timeout<F: FnOnce() + 'static>(delay: Duration, callback: F) -> TimerHandle {
+    schedule(delay, Callback::Once(Box::new(callback)))
+}
+
+/// Call callback repeatedly with given period until cancelled
+pub fn set_interval<F: FnMut() + 'static>(period: Duration, callback: F) -> TimerHandle {
+    schedule(period, Callback::Repeat(Box::new(callback)))
+}
+
+fn schedule(delay: Duration, callback: Callback) -> TimerHandle {
+    // Zero interval would never let virtual clock move forward
+    let delay = delay.max(Duration::from_millis(1));
+    let id = NEXT_ID.with(|next| {
+        let id = next.get();
+        next.set(id.wrapping_add(1).max(1));
+        id
+    });
+    let timer = Timer {
+        callback: Some(callback),
+        interval: delay,
+        #[cfg(not(all(target_os = "wasi", feature = "host-timers")))]
+        due: now() + delay,
+    };
+    TIMERS.with(|timers| timers.borrow_mut().insert(id, timer));
+    host::set_timer(id, delay);
+    TimerHandle(id)
+}
+
+/// Run callback of the timer, called by host when timer is due
+pub(crate) fn fire(id: u32) {
+    let callback = TIMERS.with(|timers| {
+        timers
+            .borrow_mut()
+            .get_mut(&id)
+            .and_then(|timer| timer.callback.take())
+    });
+    match callback {
+        Some(Callback::Once(callback)) => {
+            TIMERS.with(|timers| timers.borrow_mut().remove(&id));
+            callback();
+        }
+        Some(Callback::Repeat(mut callback)) => {
+            callback();
+            // Timer could be cancelled by its callback
+            let interval = TIMERS.with(|timers| {
+                timers.borrow_mut().get_mut(&id).map(|timer| {
+                    timer.callback = Some(Callback::Repeat(callback));
+                    #[cfg(not(all(target_os = "wasi", feature = "host-timers")))]
+                    {
+                        timer.due += timer.interval;
+                    }
+                    timer.interval
+                })
+            });
+            if let Some(interval) = interval {
+                host::set_timer(id, interval);
+            }
+        }
+        None => (),
+    }
+    executor::poll_pending();
+}
+
+/// Cancel all timers
+pub(crate) fn clear() {
+    let timers = TIMERS.with(|timers| timers.replace(HashMap::new()));
+    for id in timers.keys() {
+        host::clear_timer(*id);
+    }
+}
+
+/// Timers of worker instance which is not entered, see ServiceWorker::enter.
+///
+/// Timer ids are unique within the thread, virtual time is kept per instance.
+#[derive(Default)]
+pub(crate) struct Timers {
+    timers: HashMap<u32, Timer>,
+    #[cfg(not(all(target_os = "wasi", feature = "host-timers")))]
+    now: Duration,
+}
+
+/// Exchange timers of the current worker with given ones
+pub(crate) fn swap(other: &mut Timers) {
+    TIMERS.with(|timers| std::mem::swap(&mut *timers.borrow_mut(), &mut other.timers));
+    #[cfg(not(all(target_os = "wasi", feature = "host-timers")))]
+    NOW.with(|now| other.now = now.replace(other.now));
+}
+
+/// Future which completes after given duration, based on set_timeout
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        duration,
+        state: Rc::new(RefCell::new(SleepState::default())),
+        handle: None,
+    }
+}
+
+#[derive(Default)]
+struct SleepState {
+    done: bool,
+    waker: Option<Waker>,
+}
+
+/// Future returned by [sleep], cancels its timer when dropped
+pub struct Sleep {
+    duration: Duration,
+    state: Rc<RefCell<SleepState>>,
+    handle: Option<TimerHandle>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.borrow_mut();
+        if state.done {
+            return Poll::Ready(());
+        }
+        state.waker = Some(cx.waker().clone());
+        drop(state);
+        if self.handle.is_none() {
+            let state = self.state.clone();
+            let handle = set_timeout(self.duration, move || {
+                let mut state = state.borrow_mut();
+                state.done = true;
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            });
+            self.handle = Some(handle);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.cancel();
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "wasi", feature = "host-timers")))]
+thread_local! {
+  static NOW: Cell<Duration> = const { Cell::new(Duration::from_millis(0)) };
+}
+
+/// Current virtual time, not available with host timers
+#[cfg(not(all(target_os = "wasi", feature = "host-timers")))]
+pub fn now() -> Duration {
+    NOW.with(|now| now.get())
+}
+
+/// Move virtual time forward firing all timers which are due, in order,
+/// not available with host timers
+#[cfg(not(all(target_os = "wasi", feature = "host-timers")))]
+pub fn advance(duration: Duration) {
+    let target = now() + duration;
+    loop {
+        let next = TIMERS.with(|timers| {
+            timers
+                .borrow()
+                .iter()
+                .filter(|(_, timer)| timer.due <= target && timer.callback.is_some())
+                .map(|(id, timer)| (timer.due, *id))
+                .min()
+        });
+        match next {
+            Some((due, id)) => {
+                NOW.with(|now| now.set(due));
+                fire(id);
+            }
+            None => break,
+        }
+    }
+    NOW.with(|now| now.set(target));
+}
+
+#[cfg(all(target_os = "wasi", feature = "host-timers"))]
+mod host {
+    use std::time::Duration;
+
+    mod imports {
+        #[link(wasm_import_module = "wasi_worker")]
+        extern "C" {
+            pub fn set_timer(id: u32, delay_ms: u32);
+            pub fn clear_timer(id: u32);
+        }
+    }
+
+    pub fn set_timer(id: u32, delay: Duration) {
+        let delay_ms = delay.as_millis().min(u32::MAX as u128) as u32;
+        unsafe { imports::set_timer(id, delay_ms) }
+    }
+
+    pub fn clear_timer(id: u32) {
+        unsafe { imports::clear_timer(id) }
+    }
+}
+
+// Virtual clock does not need host, timers are fired by advance()
+#[cfg(not(all(target_os = "wasi", feature = "host-timers")))]
+mod host {
+    use std::time::Duration;
+
+    pub fn set_timer(_id: u32, _delay: Duration) {}
+
+    pub fn clear_timer(_id: u32) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_and_interval() {
+        let fired = Rc::new(RefCell::new(Vec::new()));
+        let log = fired.clone();
+        set_timeout(Duration::from_millis(100), move || {
+            log.borrow_mut().push(("timeout", now().as_millis()))
+        });
+        let log = fired.clone();
+        let interval = set_interval(Duration::from_millis(30), move || {
+            log.borrow_mut().push(("interval", now().as_millis()))
+        });
+        advance(Duration::from_millis(100));
+        interval.cancel();
+        advance(Duration::from_millis(100));
+
+        assert_eq!(
+            *fired.borrow(),
+            vec![
+                ("interval", 30),
+                ("interval", 60),
+                ("interval", 90),
+                ("timeout", 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn sleep_in_spawned_future() {
+        let done = Rc::new(Cell::new(false));
+        let flag = done.clone();
+        executor::spawn_local(async move {
+            sleep(Duration::from_millis(50)).await;
+            flag.set(true);
+        });
+        assert_eq!(executor::poll_pending(), 1);
+        advance(Duration::from_millis(49));
+        assert!(!done.get());
+        advance(Duration::from_millis(1));
+        assert!(done.get());
+        assert_eq!(executor::poll_pending(), 0);
+    }
+}
@@ -0,0 +1,283 @@
+//! `tracing` layer which forwards spans and events to the host, requires `tracing` feature.
+//!
+//! Records are posted on the reserved `$trace` channel:
+//! `[kind: u8][level: u8][timestamp: u64 LE][span id: u64 LE][name][target][fields]`,
+//! where kind is 0 for span enter, 1 for span exit and 2 for event, level is
+//! 1 (error) to 5 (trace), timestamp is microseconds since UNIX epoch and strings
+//! are prefixed with u32 LE length. Span id of event is its parent span or 0,
+//! fields are formatted as `name=value` separated by spaces.
+//!
+//! ServiceWorker::on_message dispatch and ServiceWorker::post_message calls
+//! are wrapped into `on_message` and `post_message` spans.
+//!
+//! Example usage:
+//! ```
+//! use wasi_worker::trace;
+//!
+//! trace::init().expect("trace::init");
+//! let _stage = tracing::info_span!("decode", tiles = 3).entered();
+//! tracing::info!("decoded");
+//! ```
+use super::framing::{push_field, split_field, timestamp};
+use super::{Error, Result, ServiceWorker};
+use std::fmt::{self, Write};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Channel of trace records
+pub const TRACE_CHANNEL: &str = "$trace";
+
+const ENTER: u8 = 0;
+const EXIT: u8 = 1;
+const EVENT: u8 = 2;
+
+/// Install registry with WorkerLayer as global default subscriber
+pub fn init() -> Result<(), tracing::subscriber::SetGlobalDefaultError> {
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(WorkerLayer))
+}
+
+/// Layer which posts span enter/exit and events on TRACE_CHANNEL
+pub struct WorkerLayer;
+
+// Formatted fields of the span, kept in span extensions
+struct SpanFields(String);
+
+impl<S> Layer<S> for WorkerLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = FieldWriter::default();
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(SpanFields(fields.0));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+                let mut writer = FieldWriter(std::mem::take(fields));
+                values.record(&mut writer);
+                *fields = writer.0;
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.post_span(TraceKind::Enter, id, ctx)
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.post_span(TraceKind::Exit, id, ctx)
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = FieldWriter::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        post(&TraceRecord {
+            kind: TraceKind::Event,
+            level: *metadata.level(),
+            timestamp: timestamp(),
+            span: ctx
+                .event_span(event)
+                .map(|span| span.id().into_u64())
+                .unwrap_or(0),
+            name: metadata.name().to_string(),
+            target: metadata.target().to_string(),
+            fields: fields.0,
+        })
+    }
+}
+
+impl WorkerLayer {
+    fn post_span<S>(&self, kind: TraceKind, id: &Id, ctx: Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if let Some(span) = ctx.span(id) {
+            let fields = span
+                .extensions()
+                .get::<SpanFields>()
+                .map(|fields| fields.0.clone())
+                .unwrap_or_default();
+            let metadata = span.metadata();
+            post(&TraceRecord {
+                kind,
+                level: *metadata.level(),
+                timestamp: timestamp(),
+                span: id.into_u64(),
+                name: metadata.name().to_string(),
+                target: metadata.target().to_string(),
+                fields,
+            })
+        }
+    }
+}
+
+// Records are dropped while ServiceWorker is not initialized
+fn post(record: &TraceRecord) {
+    let _ = ServiceWorker::post_channel_message(TRACE_CHANNEL, &encode_record(record));
+}
+
+#[derive(Default)]
+struct FieldWriter(String);
+
+impl Visit for FieldWriter {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        let _ = write!(self.0, "{}={:?}", field.name(), value);
+    }
+}
+
+/// What happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceKind {
+    Enter,
+    Exit,
+    Event,
+}
+
+/// Decoded trace record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub kind: TraceKind,
+    pub level: Level,
+    /// Microseconds since UNIX epoch
+    pub timestamp: u64,
+    /// Span id, for events id of parent span or 0
+    pub span: u64,
+    pub name: String,
+    pub target: String,
+    pub fields: String,
+}
+
+pub fn encode_record(record: &TraceRecord) -> Vec<u8> {
+    let kind = match record.kind {
+        TraceKind::Enter => ENTER,
+        TraceKind::Exit => EXIT,
+        TraceKind::Event => EVENT,
+    };
+    let level = match record.level {
+        Level::ERROR => 1,
+        Level::WARN => 2,
+        Level::INFO => 3,
+        Level::DEBUG => 4,
+        Level::TRACE => 5,
+    };
+    let mut data = vec![kind, level];
+    data.extend_from_slice(&record.timestamp.to_le_bytes());
+    data.extend_from_slice(&record.span.to_le_bytes());
+    push_field(&mut data, &record.name);
+    push_field(&mut data, &record.target);
+    push_field(&mut data, &record.fields);
+    data
+}
+
+pub fn decode_record(data: &[u8]) -> Result<TraceRecord> {
+    if data.len() < 18 {
+        return Err(malformed());
+    }
+    let kind = match data[0] {
+        ENTER => TraceKind::Enter,
+        EXIT => TraceKind::Exit,
+        EVENT => TraceKind::Event,
+        _ => return Err(malformed()),
+    };
+    let level = match data[1] {
+        1 => Level::ERROR,
+        2 => Level::WARN,
+        3 => Level::INFO,
+        4 => Level::DEBUG,
+        5 => Level::TRACE,
+        _ => return Err(malformed()),
+    };
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&data[2..10]);
+    let mut span = [0u8; 8];
+    span.copy_from_slice(&data[10..18]);
+    let (name, rest) = split_field(&data[18..]).ok_or_else(malformed)?;
+    let (target, rest) = split_field(rest).ok_or_else(malformed)?;
+    let (fields, _) = split_field(rest).ok_or_else(malformed)?;
+    Ok(TraceRecord {
+        kind,
+        level,
+        timestamp: u64::from_le_bytes(timestamp),
+        span: u64::from_le_bytes(span),
+        name: name.to_string(),
+        target: target.to_string(),
+        fields: fields.to_string(),
+    })
+}
+
+fn malformed() -> Error {
+    Error::Decode("Malformed trace record".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileOptions, Handler, MemoryBuffer, ServiceOptions};
+
+    struct Stage;
+    impl Handler for Stage {
+        fn on_message(&self, msg: &[u8]) -> Result<()> {
+            let _stage = tracing::info_span!("compute", size = msg.len()).entered();
+            tracing::info!(tiles = 2, "computed");
+            ServiceWorker::post_message(msg)
+        }
+    }
+
+    #[test]
+    fn spans_and_events() {
+        let input = MemoryBuffer::new();
+        let output = MemoryBuffer::new();
+        let opt = ServiceOptions::default()
+            .with_input(FileOptions::Memory(input.clone()))
+            .with_output(FileOptions::Memory(output.clone()));
+        ServiceWorker::initialize(opt).expect("ServiceWorker::initialize");
+        ServiceWorker::set_message_handler(Box::new(Stage));
+        input.push_message(b"tile");
+        let subscriber = tracing_subscriber::registry().with(WorkerLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            ServiceWorker::on_message().expect("ServiceWorker::on_message");
+        });
+        ServiceWorker::kill();
+
+        let records: Vec<TraceRecord> = output
+            .channel_messages(TRACE_CHANNEL)
+            .unwrap()
+            .iter()
+            .map(|data| decode_record(data).unwrap())
+            .collect();
+        let summary: Vec<(TraceKind, &str)> = records
+            .iter()
+            .map(|record| (record.kind, record.name.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (TraceKind::Enter, "on_message"),
+                (TraceKind::Enter, "compute"),
+                (TraceKind::Event, records[2].name.as_str()),
+                (TraceKind::Enter, "post_message"),
+                (TraceKind::Exit, "post_message"),
+                (TraceKind::Exit, "compute"),
+                (TraceKind::Exit, "on_message"),
+            ]
+        );
+        assert_eq!(records[0].fields, "channel=\"\" len=4");
+        assert_eq!(records[1].fields, "size=4");
+        assert_eq!(records[2].fields, "message=computed tiles=2");
+        assert_eq!(records[2].span, records[1].span);
+        assert!(records[0].timestamp <= records[6].timestamp);
+        assert_eq!(output.messages().unwrap(), vec![b"tile".to_vec()]);
+    }
+}